@@ -0,0 +1,393 @@
+// serde Deserializer reading from a decoded Item tree.
+
+use std::collections::btree_map;
+
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+
+use super::{decode::parse, error::Error, Item};
+
+pub struct Deserializer {
+    item: Item,
+}
+
+impl Deserializer {
+    pub fn new(item: Item) -> Self {
+        Deserializer { item }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self.item {
+            Item::Integer(_) => "integer",
+            Item::String(_) => "string",
+            Item::List(_) => "list",
+            Item::Dict(_) => "dict",
+        }
+    }
+
+    fn invalid_type(&self, exp: &dyn de::Expected) -> Error {
+        let unexp = match &self.item {
+            Item::Integer(i) => de::Unexpected::Signed(*i),
+            Item::String(s) => de::Unexpected::Bytes(s),
+            Item::List(_) => de::Unexpected::Seq,
+            Item::Dict(_) => de::Unexpected::Map,
+        };
+        de::Error::invalid_type(unexp, exp)
+    }
+}
+
+// deserializes a T from a decoded Item.
+pub fn from_item<T: DeserializeOwned>(item: Item) -> Result<T, Error> {
+    T::deserialize(Deserializer::new(item))
+}
+
+// decodes bencode bytes and deserializes them into a T.
+pub fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+    from_item(parse(bytes)?)
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.item {
+            Item::Integer(i) => visitor.visit_i64(i),
+            Item::String(s) => match String::from_utf8(s) {
+                Ok(s) => visitor.visit_string(s),
+                Err(e) => visitor.visit_byte_buf(e.into_bytes()),
+            },
+            Item::List(l) => visitor.visit_seq(ListAccess {
+                iter: l.into_iter(),
+            }),
+            Item::Dict(d) => visitor.visit_map(DictAccess {
+                iter: d.into_iter(),
+                key: None,
+                value: None,
+            }),
+        }
+    }
+
+    // bencode has no booleans, flags such as `private` are 0 or 1.
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.item {
+            Item::Integer(0) => visitor.visit_bool(false),
+            Item::Integer(1) => visitor.visit_bool(true),
+            _ => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.item {
+            Item::String(s) => match String::from_utf8(s) {
+                Ok(s) => visitor.visit_string(s),
+                Err(_) => Err(de::Error::custom("invalid utf-8 in string")),
+            },
+            _ => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.item {
+            Item::String(s) => visitor.visit_byte_buf(s),
+            _ => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    // a value that is present is always Some, absent fields are None.
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        Err(self.invalid_type(&visitor))
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // byte strings also read as a sequence of u8, so Vec<u8> works without serde_bytes.
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.item {
+            Item::List(l) => visitor.visit_seq(ListAccess {
+                iter: l.into_iter(),
+            }),
+            Item::String(s) => visitor.visit_seq(de::value::SeqDeserializer::new(s.into_iter())),
+            _ => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.item {
+            Item::Dict(d) => visitor.visit_map(DictAccess {
+                iter: d.into_iter(),
+                key: None,
+                value: None,
+            }),
+            _ => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    // unit variants are plain strings, others a dict with the variant name as only key.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.item {
+            Item::String(s) => match String::from_utf8(s) {
+                Ok(s) => visitor.visit_enum(s.into_deserializer()),
+                Err(_) => Err(de::Error::custom("invalid utf-8 in enum variant")),
+            },
+            Item::Dict(d) if d.len() == 1 => {
+                let (key, value) = d.into_iter().next().unwrap();
+                visitor.visit_enum(Enum { key, value })
+            }
+            _ => Err(de::Error::custom(format!(
+                "expected string or single key dict for enum, found {}",
+                self.kind()
+            ))),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.item {
+            Item::String(s) => visitor.visit_byte_buf(s),
+            _ => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char
+    }
+}
+
+struct ListAccess {
+    iter: std::vec::IntoIter<Item>,
+}
+
+impl<'de> SeqAccess<'de> for ListAccess {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.iter.next() {
+            Some(item) => seed.deserialize(Deserializer::new(item)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct DictAccess {
+    iter: btree_map::IntoIter<Vec<u8>, Item>,
+    // key of the pending value, kept so errors can name the field.
+    key: Option<Vec<u8>>,
+    value: Option<Item>,
+}
+
+impl<'de> MapAccess<'de> for DictAccess {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                self.key = Some(key.clone());
+                seed.deserialize(Deserializer::new(Item::String(key)))
+                    .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = match self.value.take() {
+            Some(v) => v,
+            None => return Err(de::Error::custom("value requested before key")),
+        };
+        let key = self.key.take().unwrap_or_default();
+        seed.deserialize(Deserializer::new(value))
+            .map_err(|e| e.in_field(&key))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct Enum {
+    key: Vec<u8>,
+    value: Item,
+}
+
+impl<'de> EnumAccess<'de> for Enum {
+    type Error = Error;
+    type Variant = Deserializer;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Deserializer), Error> {
+        let variant = seed.deserialize(Deserializer::new(Item::String(self.key)))?;
+        Ok((variant, Deserializer::new(self.value)))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Deserializer {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Err(de::Error::custom("unexpected value for unit variant"))
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod serde_test {
+    use crate::bencode::{from_bytes, ser::to_bytes};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Info {
+        name: String,
+        #[serde(rename = "piece length")]
+        piece_length: u64,
+        #[serde(with = "serde_bytes")]
+        pieces: Vec<u8>,
+        #[serde(default)]
+        private: bool,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Meta {
+        announce: String,
+        #[serde(rename = "announce-list")]
+        announce_list: Option<Vec<Vec<String>>>,
+        comment: Option<String>,
+        info: Info,
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let meta = Meta {
+            announce: "udp://tracker:80".to_string(),
+            announce_list: None,
+            comment: Some("hi".to_string()),
+            info: Info {
+                name: "foo".to_string(),
+                piece_length: 16384,
+                pieces: vec![0xff; 20],
+                private: true,
+            },
+        };
+        let bytes = to_bytes(&meta).unwrap();
+        assert!(bytes.starts_with(b"d8:announce16:udp://tracker:807:comment2:hi4:infod"));
+        assert_eq!(from_bytes::<Meta>(&bytes).unwrap(), meta);
+    }
+
+    #[derive(Debug, PartialEq, Serialize)]
+    enum Field {
+        Value(Option<u32>),
+    }
+
+    #[test]
+    fn test_newtype_variant_none() {
+        assert_eq!(to_bytes(&Field::Value(Some(1))).unwrap(), b"d5:Valuei1ee");
+        // skipped like a None struct field, not an error.
+        assert_eq!(to_bytes(&Field::Value(None)).unwrap(), b"de");
+    }
+
+    #[test]
+    fn test_errors_name_field() {
+        let missing = b"d8:announce1:a4:infod4:name3:foo6:pieces0:ee";
+        assert_eq!(
+            from_bytes::<Meta>(missing).unwrap_err().to_string(),
+            "field `info`: missing field `piece length`"
+        );
+
+        let mistyped = b"d8:announce1:a4:infod4:name3:foo12:piece length2:xx6:pieces0:ee";
+        let err = from_bytes::<Meta>(mistyped).unwrap_err();
+        assert_eq!(err.path, vec!["info", "piece length"]);
+
+        let negative = b"d8:announce1:a4:infod4:name3:foo12:piece lengthi-1e6:pieces0:ee";
+        let err = from_bytes::<Meta>(negative).unwrap_err();
+        assert_eq!(err.path, vec!["info", "piece length"]);
+    }
+}
@@ -1,91 +1,315 @@
-use super::Item;
-use std::collections::BTreeMap;
-
-fn parse_int(str: &mut Vec<u8>) -> usize {
-    let mut len: usize = 0;
-    let mut int_string: String = String::new();
-    for c in str.iter() {
-        len += 1;
-        if *c == b'i' {
-            continue;
+use super::{Item, ItemRef, Spanned};
+use std::{collections::BTreeMap, error::Error, fmt::Display};
+
+// lists and dicts nested deeper than this are rejected instead of recursing further.
+pub const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    // input ended in the middle of an item.
+    UnexpectedEof,
+    // byte that can't start or continue an item.
+    InvalidByte(u8),
+    // malformed or out of range integer or string length.
+    InvalidInteger,
+    // integer with leading zeros or a negative zero, i.e. i03e or i-0e.
+    NonCanonicalInteger,
+    // string length prefix larger than the remaining input.
+    StringTooLong(usize),
+    // more than one top level item.
+    TrailingData,
+    // nesting deeper than MAX_DEPTH.
+    DepthLimit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    // byte offset into the input where the failure was found.
+    pub offset: usize,
+    pub kind: DecodeErrorKind,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            DecodeErrorKind::UnexpectedEof => write!(f, "unexpected end of input"),
+            DecodeErrorKind::InvalidByte(b) => write!(f, "invalid byte {:#04x}", b),
+            DecodeErrorKind::InvalidInteger => write!(f, "invalid integer"),
+            DecodeErrorKind::NonCanonicalInteger => write!(f, "non-canonical integer"),
+            DecodeErrorKind::StringTooLong(len) => {
+                write!(f, "string length {} exceeds input", len)
+            }
+            DecodeErrorKind::TrailingData => write!(f, "trailing data after item"),
+            DecodeErrorKind::DepthLimit => write!(f, "nesting deeper than {}", MAX_DEPTH),
+        }?;
+        write!(f, " at offset {}", self.offset)
+    }
+}
+
+impl Error for DecodeError {}
+
+// walks the input with a cursor, strings borrow straight from it.
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn error(&self, offset: usize, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError { offset, kind }
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        match self.bytes.get(self.pos) {
+            Some(c) => Ok(*c),
+            None => Err(self.error(self.pos, DecodeErrorKind::UnexpectedEof)),
         }
-        if *c == b'e' {
-            break;
+    }
+
+    // reads ascii digits up to the terminator byte, leaves the cursor after it.
+    fn read_until(&mut self, end: u8) -> Result<&'a [u8], DecodeError> {
+        let start = self.pos;
+        loop {
+            let c = self.peek()?;
+            if c == end {
+                break;
+            }
+            if !c.is_ascii_digit() {
+                return Err(self.error(self.pos, DecodeErrorKind::InvalidByte(c)));
+            }
+            self.pos += 1;
         }
-        int_string.push(*c as char);
+        let digits = &self.bytes[start..self.pos];
+        self.pos += 1;
+        Ok(digits)
     }
-    str.drain(0..len);
-    int_string.parse::<usize>().unwrap()
-}
 
-fn parse_string(str: &mut Vec<u8>) -> Vec<u8> {
-    let mut int_len: usize = 0;
-    let mut int_string: String = String::new();
-    for s in str.iter() {
-        int_len += 1;
-        if *s == b':' {
-            break;
+    fn parse_int(&mut self) -> Result<i64, DecodeError> {
+        let start = self.pos;
+        self.pos += 1;
+        let negative = self.bytes.get(self.pos) == Some(&b'-');
+        if negative {
+            self.pos += 1;
+        }
+        let digits = self.read_until(b'e')?;
+        // only a lone 0 may start with 0, and it can't be negated.
+        if digits.first() == Some(&b'0') && (digits.len() > 1 || negative) {
+            return Err(self.error(start, DecodeErrorKind::NonCanonicalInteger));
+        }
+        // digits are ascii, the sign is kept so i64::MIN still fits.
+        let signed = &self.bytes[start + 1..self.pos - 1];
+        match std::str::from_utf8(signed).map(str::parse::<i64>) {
+            Ok(Ok(i)) => Ok(i),
+            _ => Err(self.error(start, DecodeErrorKind::InvalidInteger)),
         }
-        int_string.push(*s as char);
     }
-    str.drain(0..int_len);
 
-    let len = int_string.parse::<usize>().unwrap();
-    let string = str[..len].to_vec();
-    let mut clone = str[len..].to_vec();
-    str.clear();
-    str.append(&mut clone);
-    string
-}
+    fn parse_string(&mut self) -> Result<&'a [u8], DecodeError> {
+        let start = self.pos;
+        let digits = self.read_until(b':')?;
+        let len = match std::str::from_utf8(digits).map(str::parse::<usize>) {
+            Ok(Ok(l)) => l,
+            _ => return Err(self.error(start, DecodeErrorKind::InvalidInteger)),
+        };
+        if len > self.bytes.len() - self.pos {
+            return Err(self.error(start, DecodeErrorKind::StringTooLong(len)));
+        }
+        let string = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(string)
+    }
 
-fn parse_list(str: &mut Vec<u8>) -> Vec<Item> {
-    str.drain(..1);
-    let mut list = Vec::<Item>::new();
-    loop {
-        match *str.get(0).unwrap() as char {
-            'i' => list.push(Item::Integer(parse_int(str))),
-            'l' => list.push(Item::List(parse_list(str))),
-            'd' => list.push(Item::Dict(parse_dict(str))),
-            '0'..='9' => list.push(Item::String(parse_string(str))),
-            'e' => break,
-            _ => unreachable!(),
+    fn parse_item(&mut self, depth: usize) -> Result<ItemRef<'a>, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error(self.pos, DecodeErrorKind::DepthLimit));
+        }
+        match self.peek()? {
+            b'i' => Ok(ItemRef::Integer(self.parse_int()?)),
+            b'l' => Ok(ItemRef::List(self.parse_list(depth)?)),
+            b'd' => Ok(ItemRef::Dict(self.parse_dict(depth)?)),
+            b'0'..=b'9' => Ok(ItemRef::String(self.parse_string()?)),
+            c => Err(self.error(self.pos, DecodeErrorKind::InvalidByte(c))),
         }
     }
-    str.drain(..1);
-    list
-}
 
-fn parse_dict(str: &mut Vec<u8>) -> BTreeMap<Vec<u8>, Item> {
-    str.drain(0..1);
-    let mut dict: BTreeMap<Vec<u8>, Item> = BTreeMap::new();
-    loop {
-        if *str.get(0).unwrap() == b'e' {
-            break;
+    fn parse_list(&mut self, depth: usize) -> Result<Vec<ItemRef<'a>>, DecodeError> {
+        self.pos += 1;
+        let mut list = Vec::new();
+        while self.peek()? != b'e' {
+            list.push(self.parse_item(depth + 1)?);
         }
-        let s = parse_string(str);
-        match *str.get(0).unwrap() as char {
-            'i' => dict.insert(s, Item::Integer(parse_int(str))),
-            'l' => dict.insert(s, Item::List(parse_list(str))),
-            'd' => dict.insert(s, Item::Dict(parse_dict(str))),
-            '0'..='9' => dict.insert(s, Item::String(parse_string(str))),
-            _ => unreachable!(),
-        };
+        self.pos += 1;
+        Ok(list)
     }
-    str.drain(0..1);
-    dict
-}
 
-pub fn parse(str: &mut Vec<u8>) -> Vec<Item> {
-    // parse readed content from torrent file to Items, and push Item to Vec<Item>
-    let mut items: Vec<Item> = Vec::new();
-    while let Some(c) = str.get(0) {
-        match *c {
-            b'i' => items.push(Item::Integer(parse_int(str))),
-            b'l' => items.push(Item::List(parse_list(str))),
-            b'd' => items.push(Item::Dict(parse_dict(str))),
-            b'0'..=b'9' => items.push(Item::String(parse_string(str))),
-            _ => break,
+    fn parse_dict(&mut self, depth: usize) -> Result<BTreeMap<&'a [u8], Spanned<'a>>, DecodeError> {
+        self.pos += 1;
+        let mut dict = BTreeMap::new();
+        loop {
+            match self.peek()? {
+                b'e' => break,
+                // keys are always strings.
+                b'0'..=b'9' => {}
+                c => return Err(self.error(self.pos, DecodeErrorKind::InvalidByte(c))),
+            }
+            let key = self.parse_string()?;
+            let start = self.pos;
+            let item = self.parse_item(depth + 1)?;
+            let raw = &self.bytes[start..self.pos];
+            dict.insert(key, Spanned { item, raw });
         }
+        self.pos += 1;
+        Ok(dict)
+    }
+}
+
+// parses exactly one ItemRef borrowing from bytes, anything left over is an error.
+pub fn parse_ref(bytes: &[u8]) -> Result<ItemRef<'_>, DecodeError> {
+    let mut decoder = Decoder { bytes, pos: 0 };
+    let item = decoder.parse_item(0)?;
+    if decoder.pos != bytes.len() {
+        return Err(decoder.error(decoder.pos, DecodeErrorKind::TrailingData));
+    }
+    Ok(item)
+}
+
+// parses one leading Item and returns how many bytes it took, for messages
+// that carry raw data after a bencoded header.
+pub fn parse_prefix(bytes: &[u8]) -> Result<(Item, usize), DecodeError> {
+    let mut decoder = Decoder { bytes, pos: 0 };
+    let item = decoder.parse_item(0)?;
+    Ok((Item::from(item), decoder.pos))
+}
+
+// parses exactly one owned Item, anything left over is an error.
+pub fn parse(bytes: &[u8]) -> Result<Item, DecodeError> {
+    parse_ref(bytes).map(Item::from)
+}
+
+#[cfg(test)]
+mod decode_test {
+    use super::*;
+
+    fn parse_err(bytes: &[u8]) -> DecodeError {
+        parse(bytes).unwrap_err()
+    }
+
+    #[test]
+    fn test_parse_nested() {
+        let item = parse(b"d1:ali1ei2ee1:b3:fooe").unwrap();
+        assert!(matches!(item.get("a"), Some(Item::List(l)) if l.len() == 2));
+        assert_eq!(item.get("b").unwrap().as_bytes().unwrap(), b"foo");
+    }
+
+    #[test]
+    fn test_parse_ref_borrows_input() {
+        let bytes = b"d6:pieces4:abcde";
+        let item = parse_ref(bytes).unwrap();
+        let pieces = match item {
+            ItemRef::Dict(d) => match d.get(b"pieces".as_slice()).map(|v| &v.item) {
+                Some(ItemRef::String(s)) => *s,
+                _ => panic!("expected pieces string"),
+            },
+            _ => panic!("expected dict"),
+        };
+        assert_eq!(pieces, b"abcd");
+        assert!(std::ptr::eq(pieces.as_ptr(), bytes[11..].as_ptr()));
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        let (item, len) = parse_prefix(b"d1:ai1eeraw bytes").unwrap();
+        assert_eq!(item.get("a").unwrap().as_int(), Some(1));
+        assert_eq!(len, 8);
+        assert!(parse_prefix(b"d1:a").is_err());
+    }
+
+    #[test]
+    fn test_raw_dict_value_span() {
+        // info isn't the last key and a string elsewhere contains 4:infod.
+        let bytes = b"d7:comment7:4:infod4:infod4:name1:ae8:url-listle5:zzzzzi1ee";
+        let item = parse_ref(bytes).unwrap();
+        assert_eq!(item.get_raw(b"info").unwrap(), b"d4:name1:ae");
+        assert_eq!(item.get_raw(b"url-list").unwrap(), b"le");
+        assert_eq!(item.get_raw(b"zzzzz").unwrap(), b"i1e");
+        assert_eq!(item.get_raw(b"missing"), None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse_err(b"").kind, DecodeErrorKind::UnexpectedEof);
+        assert_eq!(parse_err(b"i12").kind, DecodeErrorKind::UnexpectedEof);
+        assert_eq!(
+            parse_err(b"d3:fooi1e"),
+            DecodeError {
+                offset: 9,
+                kind: DecodeErrorKind::UnexpectedEof
+            }
+        );
+        assert_eq!(
+            parse_err(b"i1x2e"),
+            DecodeError {
+                offset: 2,
+                kind: DecodeErrorKind::InvalidByte(b'x')
+            }
+        );
+        assert_eq!(parse_err(b"ie").kind, DecodeErrorKind::InvalidInteger);
+        assert_eq!(parse_err(b"i-e").kind, DecodeErrorKind::InvalidInteger);
+        assert_eq!(
+            parse_err(b"i9223372036854775808e").kind,
+            DecodeErrorKind::InvalidInteger
+        );
+        assert_eq!(parse_err(b"5:ab").kind, DecodeErrorKind::StringTooLong(5));
+        assert_eq!(
+            parse_err(b"di1ei2ee"),
+            DecodeError {
+                offset: 1,
+                kind: DecodeErrorKind::InvalidByte(b'i')
+            }
+        );
+        assert_eq!(
+            parse_err(b"i1ei2e"),
+            DecodeError {
+                offset: 3,
+                kind: DecodeErrorKind::TrailingData
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_signed_integers() {
+        assert_eq!(parse(b"i-1e").unwrap().as_int().unwrap(), -1);
+        assert_eq!(parse(b"i0e").unwrap().as_int().unwrap(), 0);
+        assert_eq!(
+            parse(b"i5000000000e").unwrap().as_int().unwrap(),
+            5_000_000_000
+        );
+        assert_eq!(
+            parse(b"i-9223372036854775808e").unwrap().as_int().unwrap(),
+            i64::MIN
+        );
+        assert_eq!(
+            parse_err(b"i-0e").kind,
+            DecodeErrorKind::NonCanonicalInteger
+        );
+        assert_eq!(
+            parse_err(b"i03e").kind,
+            DecodeErrorKind::NonCanonicalInteger
+        );
+        assert_eq!(
+            parse_err(b"i-03e").kind,
+            DecodeErrorKind::NonCanonicalInteger
+        );
+    }
+
+    #[test]
+    fn test_parse_depth_limit() {
+        let mut deep = vec![b'l'; MAX_DEPTH + 2];
+        deep.extend(vec![b'e'; MAX_DEPTH + 2]);
+        assert_eq!(parse_err(&deep).kind, DecodeErrorKind::DepthLimit);
+
+        let mut ok = vec![b'l'; MAX_DEPTH + 1];
+        ok.extend(vec![b'e'; MAX_DEPTH + 1]);
+        assert!(parse(&ok).is_ok());
     }
-    items
 }
@@ -0,0 +1,89 @@
+// bencode encoder, writes Items back out as canonical bencode.
+
+use super::Item;
+use std::collections::BTreeMap;
+
+fn encode_int(int: i64, buf: &mut Vec<u8>) {
+    // i<base10>e, Display never emits leading zeros.
+    buf.push(b'i');
+    buf.extend_from_slice(int.to_string().as_bytes());
+    buf.push(b'e');
+}
+
+fn encode_string(str: &[u8], buf: &mut Vec<u8>) {
+    // <len>:<bytes>
+    buf.extend_from_slice(str.len().to_string().as_bytes());
+    buf.push(b':');
+    buf.extend_from_slice(str);
+}
+
+fn encode_list(list: &[Item], buf: &mut Vec<u8>) {
+    buf.push(b'l');
+    for item in list {
+        encode_item(item, buf);
+    }
+    buf.push(b'e');
+}
+
+fn encode_dict(dict: &BTreeMap<Vec<u8>, Item>, buf: &mut Vec<u8>) {
+    // BTreeMap iterates in raw byte order, which is what the spec asks for.
+    buf.push(b'd');
+    for (key, value) in dict {
+        encode_string(key, buf);
+        encode_item(value, buf);
+    }
+    buf.push(b'e');
+}
+
+fn encode_item(item: &Item, buf: &mut Vec<u8>) {
+    match item {
+        Item::Integer(i) => encode_int(*i, buf),
+        Item::String(s) => encode_string(s, buf),
+        Item::List(l) => encode_list(l, buf),
+        Item::Dict(d) => encode_dict(d, buf),
+    }
+}
+
+// encodes a single Item to bencode bytes.
+pub fn encode(item: &Item) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::new();
+    encode_item(item, &mut buf);
+    buf
+}
+
+#[cfg(test)]
+mod encode_test {
+    use super::*;
+    use crate::bencode::decode::parse;
+
+    #[test]
+    fn test_encode_scalars() {
+        assert_eq!(encode(&Item::Integer(0)), b"i0e");
+        assert_eq!(encode(&Item::Integer(1024)), b"i1024e");
+        assert_eq!(encode(&Item::Integer(-42)), b"i-42e");
+        assert_eq!(encode(&Item::String(b"spam".to_vec())), b"4:spam");
+        assert_eq!(encode(&Item::String(vec![])), b"0:");
+    }
+
+    #[test]
+    fn test_encode_sorts_dict_keys() {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"zoo".to_vec(), Item::Integer(1));
+        dict.insert(b"bar".to_vec(), Item::String(b"x".to_vec()));
+        dict.insert(
+            b"list".to_vec(),
+            Item::List(vec![Item::Integer(2), Item::String(b"ab".to_vec())]),
+        );
+        assert_eq!(
+            encode(&Item::Dict(dict)),
+            b"d3:bar1:x4:listli2e2:abe3:zooi1ee".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = b"d8:announce14:http://x/a/ann4:infod6:lengthi12e4:name3:foo12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee".to_vec();
+        let tree = parse(&bytes).unwrap();
+        assert_eq!(encode(&tree), bytes);
+    }
+}
@@ -0,0 +1,58 @@
+// error shared by the serde Serializer and Deserializer.
+
+use std::fmt::Display;
+
+use super::decode::DecodeError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    // dict keys leading to the failing value, outermost first.
+    pub path: Vec<String>,
+    pub msg: String,
+}
+
+impl Error {
+    pub fn new(msg: impl Display) -> Self {
+        Error {
+            path: vec![],
+            msg: msg.to_string(),
+        }
+    }
+
+    // records the dict key the error passed through on its way out.
+    pub fn in_field(mut self, key: &[u8]) -> Self {
+        self.path
+            .insert(0, String::from_utf8_lossy(key).into_owned());
+        self
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.msg)
+        } else {
+            write!(f, "field `{}`: {}", self.path.join("."), self.msg)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl serde::ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::new(msg)
+    }
+}
+
+impl serde::de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::new(msg)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Error::new(e)
+    }
+}
@@ -1,46 +1,125 @@
+pub mod de;
 pub mod decode;
 pub mod encode;
+pub mod error;
+pub mod ser;
+pub use de::from_bytes;
+pub use ser::to_bytes;
 use std::collections::BTreeMap;
 
 #[derive(Clone, Debug)]
 pub enum Item {
-    Integer(usize),
+    Integer(i64),
     // question: why use Vec<u8>? not String?
     String(Vec<u8>),
     List(Vec<Item>),
     Dict(BTreeMap<Vec<u8>, Item>),
 }
 
+// borrowed form of Item, strings and keys are slices into the decoded input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ItemRef<'a> {
+    Integer(i64),
+    String(&'a [u8]),
+    List(Vec<ItemRef<'a>>),
+    Dict(BTreeMap<&'a [u8], Spanned<'a>>),
+}
+
+// dict value along with the exact bytes it was decoded from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spanned<'a> {
+    pub item: ItemRef<'a>,
+    pub raw: &'a [u8],
+}
+
+impl<'a> ItemRef<'a> {
+    // raw bencoded bytes of a dict value, i.e. what info_hash is computed over.
+    pub fn get_raw(&self, key: &[u8]) -> Option<&'a [u8]> {
+        match self {
+            ItemRef::Dict(d) => d.get(key).map(|v| v.raw),
+            _ => None,
+        }
+    }
+}
+
+impl From<ItemRef<'_>> for Item {
+    fn from(item: ItemRef<'_>) -> Self {
+        match item {
+            ItemRef::Integer(i) => Item::Integer(i),
+            ItemRef::String(s) => Item::String(s.to_vec()),
+            ItemRef::List(l) => Item::List(l.into_iter().map(Item::from).collect()),
+            ItemRef::Dict(d) => Item::Dict(
+                d.into_iter()
+                    .map(|(k, v)| (k.to_vec(), Item::from(v.item)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
 impl Item {
-    pub fn get_integer(&self) -> usize {
-        let value = match self {
-            Item::Integer(i) => i,
-            _ => panic!("expected integer"),
-        };
-        *value
-    }
-
-    pub fn get_string(&self) -> Vec<u8> {
-        let value = match self {
-            Item::String(s) => s,
-            _ => panic!("expected string"),
-        };
-        value.clone()
-    }
-
-    pub fn get_list(&self) -> Vec<Item> {
-        let value = match self {
-            Item::List(l) => l,
-            _ => panic!("expected list"),
-        };
-        value.clone()
-    }
-
-    pub fn get_dict(&self) -> BTreeMap<Vec<u8>, Item> {
-        let value = match self {
-            Item::Dict(d) => d,
-            _ => panic!("expected dict"),
-        };
-        value.clone()
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Item::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    // checked conversion for lengths and counts, None if negative or too large for usize.
+    pub fn as_usize(&self) -> Option<usize> {
+        usize::try_from(self.as_int()?).ok()
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Item::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Item>> {
+        match self {
+            Item::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    // looks up a key of a dict.
+    pub fn get(&self, key: &str) -> Option<&Item> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+
+    // follows nested dict keys, i.e. get_path(&["info", "files"]).
+    pub fn get_path(&self, path: &[&str]) -> Option<&Item> {
+        path.iter().try_fold(self, |item, key| item.get(key))
+    }
+}
+
+#[cfg(test)]
+mod item_test {
+    use super::decode::parse;
+
+    #[test]
+    fn test_accessors() {
+        let item = parse(b"d4:infod4:filed6:lengthi-1ee4:name3:fooe3:num3:\xff\xfe\x00e").unwrap();
+        let info = item.get("info").unwrap();
+        assert_eq!(
+            info.get("name").and_then(|n| n.as_bytes()),
+            Some(&b"foo"[..])
+        );
+        assert_eq!(info.get("name").and_then(|n| n.as_int()), None);
+        assert!(info.as_dict().is_some());
+        assert!(info.as_bytes().is_none());
+
+        let length = item.get_path(&["info", "file", "length"]).unwrap();
+        assert_eq!(length.as_int(), Some(-1));
+        assert_eq!(length.as_usize(), None);
+
+        assert!(item.get_path(&["info", "missing"]).is_none());
+        assert!(item.get_path(&["info", "name", "deeper"]).is_none());
+        assert_eq!(
+            item.get("num").unwrap().as_bytes(),
+            Some(&b"\xff\xfe\x00"[..])
+        );
     }
 }
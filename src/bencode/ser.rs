@@ -0,0 +1,522 @@
+// serde Serializer building an Item tree, then encoded to bytes.
+
+use std::collections::BTreeMap;
+
+use serde::{
+    ser::{self, Impossible},
+    Serialize,
+};
+
+use super::{encode::encode, error::Error, Item};
+
+// Ok(None) is what `None` serializes to, dicts leave those keys out.
+pub struct Serializer;
+
+// serializes a T to an Item.
+pub fn to_item<T: Serialize + ?Sized>(value: &T) -> Result<Item, Error> {
+    match value.serialize(Serializer)? {
+        Some(item) => Ok(item),
+        None => Err(Error::new("cannot serialize None at top level")),
+    }
+}
+
+// serializes a T to canonical bencode bytes.
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    Ok(encode(&to_item(value)?))
+}
+
+fn unsupported(kind: &str) -> Error {
+    Error::new(format!("bencode has no {} type", kind))
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Option<Item>;
+    type Error = Error;
+
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeVariant<SerializeList>;
+    type SerializeMap = SerializeDict;
+    type SerializeStruct = SerializeDict;
+    type SerializeStructVariant = SerializeVariant<SerializeDict>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Error> {
+        Ok(Some(Item::Integer(v as i64)))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Error> {
+        Ok(Some(Item::Integer(v)))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Error> {
+        match i64::try_from(v) {
+            Ok(i) => self.serialize_i64(i),
+            Err(_) => Err(Error::new(format!("integer {} out of range", v))),
+        }
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok, Error> {
+        Err(unsupported("float"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok, Error> {
+        Err(unsupported("float"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Error> {
+        self.serialize_str(v.encode_utf8(&mut [0u8; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Error> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Error> {
+        Ok(Some(Item::String(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Error> {
+        Err(unsupported("unit"))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        // a None inside is left out, as it is in any other dict.
+        wrap_variant(variant, value.serialize(self)?)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Ok(SerializeList {
+            list: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Ok(SerializeVariant {
+            variant,
+            inner: self.serialize_seq(Some(len))?,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Ok(SerializeDict {
+            dict: BTreeMap::new(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Ok(SerializeVariant {
+            variant,
+            inner: self.serialize_map(Some(len))?,
+        })
+    }
+}
+
+pub struct SerializeList {
+    list: Vec<Item>,
+}
+
+impl SerializeList {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        match value.serialize(Serializer)? {
+            Some(item) => self.list.push(item),
+            None => return Err(Error::new("cannot serialize None in a list")),
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Option<Item>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(Some(Item::List(self.list)))
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Option<Item>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Option<Item>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+pub struct SerializeDict {
+    dict: BTreeMap<Vec<u8>, Item>,
+    key: Option<Vec<u8>>,
+}
+
+impl SerializeDict {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: Vec<u8>, value: &T) -> Result<(), Error> {
+        // None leaves the key out, that's how optional fields are written.
+        if let Some(item) = value.serialize(Serializer).map_err(|e| e.in_field(&key))? {
+            self.dict.insert(key, item);
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for SerializeDict {
+    type Ok = Option<Item>;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(key.serialize(KeySerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        match self.key.take() {
+            Some(key) => self.insert(key, value),
+            None => Err(Error::new("value serialized before key")),
+        }
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(Some(Item::Dict(self.dict)))
+    }
+}
+
+impl ser::SerializeStruct for SerializeDict {
+    type Ok = Option<Item>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+// wraps a list or dict in a single key dict named after the variant.
+pub struct SerializeVariant<S> {
+    variant: &'static str,
+    inner: S,
+}
+
+fn wrap_variant(variant: &'static str, item: Option<Item>) -> Result<Option<Item>, Error> {
+    let mut dict = BTreeMap::new();
+    if let Some(item) = item {
+        dict.insert(variant.as_bytes().to_vec(), item);
+    }
+    Ok(Some(Item::Dict(dict)))
+}
+
+impl ser::SerializeTupleVariant for SerializeVariant<SerializeList> {
+    type Ok = Option<Item>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.inner.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        wrap_variant(self.variant, ser::SerializeSeq::end(self.inner)?)
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeVariant<SerializeDict> {
+    type Ok = Option<Item>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.inner.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        wrap_variant(self.variant, ser::SerializeMap::end(self.inner)?)
+    }
+}
+
+// dict keys must be byte strings.
+struct KeySerializer;
+
+fn key_error() -> Error {
+    Error::new("dict keys must be strings")
+}
+
+impl ser::Serializer for KeySerializer {
+    type Ok = Vec<u8>;
+    type Error = Error;
+
+    type SerializeSeq = Impossible<Vec<u8>, Error>;
+    type SerializeTuple = Impossible<Vec<u8>, Error>;
+    type SerializeTupleStruct = Impossible<Vec<u8>, Error>;
+    type SerializeTupleVariant = Impossible<Vec<u8>, Error>;
+    type SerializeMap = Impossible<Vec<u8>, Error>;
+    type SerializeStruct = Impossible<Vec<u8>, Error>;
+    type SerializeStructVariant = Impossible<Vec<u8>, Error>;
+
+    fn serialize_str(self, v: &str) -> Result<Vec<u8>, Error> {
+        Ok(v.as_bytes().to_vec())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(v.to_vec())
+    }
+
+    fn serialize_char(self, v: char) -> Result<Vec<u8>, Error> {
+        self.serialize_str(v.encode_utf8(&mut [0u8; 4]))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Vec<u8>, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Vec<u8>, Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_bool(self, _v: bool) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_i8(self, _v: i8) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_i16(self, _v: i16) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_i32(self, _v: i32) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_i64(self, _v: i64) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_u8(self, _v: u8) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_u16(self, _v: u16) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_u32(self, _v: u32) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_u64(self, _v: u64) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_none(self) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_unit(self) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Vec<u8>, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(key_error())
+    }
+}
@@ -0,0 +1,421 @@
+// builds .torrent files from a file or directory on disk.
+
+use std::{
+    fs::{self, File},
+    io::{Error, ErrorKind, Read},
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{
+    bencode::to_bytes,
+    hash::{spawn_hash_pieces, Hasher},
+    tcp_bt::msg::structs::Piece,
+};
+
+// picked piece lengths stay within these bounds, powers of two.
+const MIN_PIECE_LEN: usize = 1 << 14; // 16 KiB
+const MAX_PIECE_LEN: usize = 1 << 24; // 16 MiB
+
+// aim for roughly this many pieces when picking a piece length.
+const TARGET_PIECES: usize = 1500;
+
+#[derive(Serialize)]
+struct RawTorrent<'a> {
+    announce: Option<&'a str>,
+    #[serde(rename = "announce-list")]
+    announce_list: Option<&'a [Vec<String>]>,
+    comment: Option<&'a str>,
+    #[serde(rename = "created by")]
+    created_by: Option<&'a str>,
+    #[serde(rename = "creation date")]
+    creation_date: Option<u64>,
+    #[serde(rename = "url-list")]
+    url_list: Option<&'a [String]>,
+    info: RawInfo,
+}
+
+#[derive(Serialize)]
+struct RawInfo {
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: usize,
+    #[serde(with = "serde_bytes")]
+    pieces: Vec<u8>,
+    private: Option<u8>,
+    length: Option<usize>,
+    files: Option<Vec<RawFile>>,
+}
+
+#[derive(Serialize)]
+struct RawFile {
+    length: usize,
+    path: Vec<String>,
+}
+
+// a file to hash, path on disk and components relative to the torrent root.
+struct SourceFile {
+    disk_path: PathBuf,
+    path: Vec<String>,
+    length: usize,
+}
+
+pub struct TorrentBuilder {
+    root: PathBuf,
+    announce: Option<String>,
+    announce_list: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    private: bool,
+    url_list: Vec<String>,
+    piece_length: Option<usize>,
+    threads: usize,
+}
+
+impl TorrentBuilder {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        TorrentBuilder {
+            root: root.into(),
+            announce: None,
+            announce_list: vec![],
+            comment: None,
+            created_by: Some(format!(
+                "{}/{}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )),
+            private: false,
+            url_list: vec![],
+            piece_length: None,
+            threads: num_cpus::get(),
+        }
+    }
+
+    pub fn announce(mut self, url: impl Into<String>) -> Self {
+        self.announce = Some(url.into());
+        self
+    }
+
+    // BEP 12 tiers, each a list of tracker urls.
+    pub fn announce_list(mut self, tiers: Vec<Vec<String>>) -> Self {
+        self.announce_list = tiers;
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: impl Into<String>) -> Self {
+        self.created_by = Some(created_by.into());
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    // BEP 19 web seeds.
+    pub fn url_list(mut self, urls: Vec<String>) -> Self {
+        self.url_list = urls;
+        self
+    }
+
+    // must be a power of two of at least 16 KiB, picked from the total size when unset.
+    pub fn piece_length(mut self, len: usize) -> Self {
+        self.piece_length = Some(len);
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    // hashes every file and returns the bencoded .torrent.
+    pub fn build(self) -> Result<Vec<u8>, Error> {
+        let name = match self.root.file_name().and_then(|n| n.to_str()) {
+            Some(n) => n.to_string(),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "root has no utf-8 file name",
+                ))
+            }
+        };
+        let single = fs::metadata(&self.root)?.is_file();
+        let mut files = vec![];
+        walk(&self.root, &mut vec![], &mut files)?;
+        let total_len: usize = files.iter().map(|f| f.length).sum();
+
+        let piece_length = match self.piece_length {
+            Some(len) if len < MIN_PIECE_LEN || !len.is_power_of_two() => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("piece length {} is not a power of two >= 16 KiB", len),
+                ))
+            }
+            Some(len) => len,
+            None => pick_piece_length(total_len),
+        };
+        let pieces = hash_files(&files, piece_length, total_len, self.threads)?;
+
+        let info = RawInfo {
+            name,
+            piece_length,
+            pieces,
+            private: if self.private { Some(1) } else { None },
+            length: if single { Some(total_len) } else { None },
+            files: if single {
+                None
+            } else {
+                Some(
+                    files
+                        .into_iter()
+                        .map(|f| RawFile {
+                            length: f.length,
+                            path: f.path,
+                        })
+                        .collect(),
+                )
+            },
+        };
+        let torrent = RawTorrent {
+            announce: self.announce.as_deref(),
+            announce_list: non_empty(&self.announce_list),
+            comment: self.comment.as_deref(),
+            created_by: self.created_by.as_deref(),
+            creation_date: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs()),
+            url_list: non_empty(&self.url_list),
+            info,
+        };
+        to_bytes(&torrent).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+fn non_empty<T>(list: &[T]) -> Option<&[T]> {
+    if list.is_empty() {
+        None
+    } else {
+        Some(list)
+    }
+}
+
+// smallest power of two giving at most TARGET_PIECES pieces, within bounds.
+pub fn pick_piece_length(total_len: usize) -> usize {
+    let mut len = MIN_PIECE_LEN;
+    while len < MAX_PIECE_LEN && total_len.div_ceil(len) > TARGET_PIECES {
+        len <<= 1;
+    }
+    len
+}
+
+// collects files under path in sorted order, so the same tree always hashes the same.
+fn walk(path: &Path, rel: &mut Vec<String>, files: &mut Vec<SourceFile>) -> Result<(), Error> {
+    let mut meta = fs::symlink_metadata(path)?;
+    // links to files are followed, links to directories skipped so they can't loop.
+    if meta.file_type().is_symlink() {
+        meta = fs::metadata(path)?;
+        if meta.is_dir() {
+            return Ok(());
+        }
+    }
+    if meta.is_file() {
+        files.push(SourceFile {
+            disk_path: path.to_path_buf(),
+            path: rel.clone(),
+            length: meta.len() as usize,
+        });
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = match entry.file_name().into_string() {
+            Ok(n) => n,
+            Err(n) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("file name {:?} is not utf-8", n),
+                ))
+            }
+        };
+        rel.push(name);
+        walk(&entry.path(), rel, files)?;
+        rel.pop();
+    }
+    Ok(())
+}
+
+// reads files back to back, cutting pieces across file boundaries, and hashes them on
+// the hasher thread pool.
+fn hash_files(
+    files: &[SourceFile],
+    piece_length: usize,
+    total_len: usize,
+    threads: usize,
+) -> Result<Vec<u8>, Error> {
+    let num_pieces = total_len.div_ceil(piece_length);
+    let hasher = Arc::new(Hasher::new());
+    let hashes = Arc::new(Mutex::new(vec![[0u8; 20]; num_pieces]));
+    let handles = spawn_hash_pieces(&hasher, &hashes, threads);
+
+    let result = queue_pieces(files, piece_length, threads * 2, &hasher);
+
+    // let the pool drain what's queued, then stop it.
+    drop(
+        hasher
+            .empty
+            .wait_while(hasher.queue.lock().unwrap(), |q| !q.is_empty())
+            .unwrap(),
+    );
+    hasher.brk.store(true, Ordering::Relaxed);
+    hasher.loops.notify_all();
+    for t in handles {
+        t.join().unwrap();
+    }
+    result?;
+
+    let hashes = hashes.lock().unwrap();
+    Ok(hashes.concat())
+}
+
+fn queue_pieces(
+    files: &[SourceFile],
+    piece_length: usize,
+    max_queued: usize,
+    hasher: &Arc<Hasher>,
+) -> Result<(), Error> {
+    let mut index = 0_u32;
+    let mut buf: Vec<u8> = Vec::with_capacity(piece_length);
+    let push = |data: Vec<u8>, index: u32| {
+        let piece = Piece {
+            index,
+            data,
+            ..Piece::default()
+        };
+        // wait for room so large trees aren't read into memory all at once.
+        let mut q = hasher
+            .empty
+            .wait_while(hasher.queue.lock().unwrap(), |q| q.len() >= max_queued)
+            .unwrap();
+        q.push_back(vec![piece]);
+        hasher.loops.notify_one();
+    };
+
+    for f in files {
+        let mut file = File::open(&f.disk_path)?;
+        let mut left = f.length;
+        while left > 0 {
+            let want = (piece_length - buf.len()).min(left);
+            let start = buf.len();
+            buf.resize(start + want, 0);
+            file.read_exact(&mut buf[start..])?;
+            left -= want;
+            if buf.len() == piece_length {
+                push(
+                    std::mem::replace(&mut buf, Vec::with_capacity(piece_length)),
+                    index,
+                );
+                index += 1;
+            }
+        }
+    }
+    if !buf.is_empty() {
+        push(buf, index);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod create_test {
+    use super::*;
+    use crate::metainfo::Metainfo;
+    use sha1::{Digest, Sha1};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("torrent-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_pick_piece_length() {
+        assert_eq!(pick_piece_length(0), MIN_PIECE_LEN);
+        assert_eq!(pick_piece_length(1500 * MIN_PIECE_LEN), MIN_PIECE_LEN);
+        assert_eq!(
+            pick_piece_length(1500 * MIN_PIECE_LEN + 1),
+            MIN_PIECE_LEN * 2
+        );
+        assert_eq!(pick_piece_length(usize::MAX / 2), MAX_PIECE_LEN);
+    }
+
+    #[test]
+    fn test_build_directory() {
+        let dir = temp_dir("create");
+        let root = dir.join("data");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let a: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let b: Vec<u8> = (0..30000).map(|i| (i * 7) as u8).collect();
+        fs::write(root.join("a.bin"), &a).unwrap();
+        fs::write(root.join("sub").join("b.bin"), &b).unwrap();
+        // a link back up the tree is skipped rather than walked forever.
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&root, root.join("sub").join("loop")).unwrap();
+
+        let bytes = TorrentBuilder::new(&root)
+            .announce("udp://tracker:80")
+            .announce_list(vec![vec!["udp://tracker:80".to_string()]])
+            .comment("test")
+            .private(true)
+            .url_list(vec!["http://mirror/".to_string()])
+            .piece_length(1 << 14)
+            .threads(3)
+            .build()
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let meta = Metainfo::from_bytes(&bytes).unwrap();
+        meta.validate().unwrap();
+        assert_eq!(meta.name(), "data");
+        assert_eq!(meta.announce(), Some("udp://tracker:80"));
+        assert_eq!(meta.comment(), Some("test"));
+        assert!(meta.private());
+        assert_eq!(meta.url_list(), ["http://mirror/".to_string()]);
+        assert_eq!(meta.files()[0].path, ["data", "a.bin"]);
+        assert_eq!(meta.files()[1].path, ["data", "sub", "b.bin"]);
+
+        // pieces span the boundary between a.bin and b.bin.
+        let all = [a, b].concat();
+        let expected: Vec<u8> = all
+            .chunks(1 << 14)
+            .flat_map(|c| Sha1::digest(c).to_vec())
+            .collect();
+        assert_eq!(meta.pieces(), &expected[..]);
+    }
+
+    #[test]
+    fn test_build_single_file() {
+        let dir = temp_dir("create-single");
+        let path = dir.join("file.txt");
+        fs::write(&path, b"hello world").unwrap();
+        let bytes = TorrentBuilder::new(&path).build().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let meta = Metainfo::from_bytes(&bytes).unwrap();
+        meta.validate().unwrap();
+        assert_eq!(meta.files()[0].path, ["file.txt"]);
+        assert_eq!(meta.total_length(), 11);
+        assert_eq!(meta.pieces(), &Sha1::digest(b"hello world")[..]);
+        assert!(!meta.private());
+    }
+}
@@ -0,0 +1,333 @@
+// KRPC, the bencoded query/response messages DHT nodes exchange over UDP.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::bencode::{from_bytes, to_bytes};
+
+pub type NodeId = [u8; 20];
+
+// 20 byte id, 4 byte ip and 2 byte port.
+pub const COMPACT_NODE_LEN: usize = 26;
+
+// error codes
+pub const PROTOCOL_ERROR: i64 = 203;
+pub const METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode(NodeId),
+    GetPeers([u8; 20]),
+    // implied_port asks the receiver to use the port the query came from.
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+    // a method we don't know, answered with METHOD_UNKNOWN.
+    Unknown(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub nodes: Vec<NodeInfo>,
+    // get_peers, peers of the info hash.
+    pub values: Vec<SocketAddr>,
+    // get_peers, to be sent back in announce_peer.
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query(Query),
+    Response(Response),
+    Error(i64, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Krpc {
+    // transaction id, echoed in the response.
+    pub t: Vec<u8>,
+    // the sender's node id, zeroes for errors.
+    pub id: NodeId,
+    pub body: Body,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct RawMsg {
+    #[serde(with = "serde_bytes")]
+    t: Vec<u8>,
+    y: String,
+    q: Option<String>,
+    a: Option<RawBody>,
+    r: Option<RawBody>,
+    e: Option<(i64, String)>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct RawBody {
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    target: Option<Vec<u8>>,
+    #[serde(default, with = "serde_bytes")]
+    info_hash: Option<Vec<u8>>,
+    #[serde(default, with = "serde_bytes")]
+    token: Option<Vec<u8>>,
+    #[serde(default, with = "serde_bytes")]
+    nodes: Option<Vec<u8>>,
+    values: Option<Vec<ByteBuf>>,
+    port: Option<u16>,
+    implied_port: Option<i64>,
+}
+
+impl Krpc {
+    pub fn query(t: Vec<u8>, id: NodeId, query: Query) -> Self {
+        Self {
+            t,
+            id,
+            body: Body::Query(query),
+        }
+    }
+
+    pub fn response(t: Vec<u8>, id: NodeId, response: Response) -> Self {
+        Self {
+            t,
+            id,
+            body: Body::Response(response),
+        }
+    }
+
+    pub fn error(t: Vec<u8>, code: i64, msg: &str) -> Self {
+        Self {
+            t,
+            id: [0; 20],
+            body: Body::Error(code, msg.to_string()),
+        }
+    }
+
+    // None for anything that isn't a well formed message.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let raw: RawMsg = from_bytes(bytes).ok()?;
+        let id = |body: &RawBody| -> Option<NodeId> { body.id.as_slice().try_into().ok() };
+        let hash = |h: &Option<Vec<u8>>| -> Option<[u8; 20]> { h.as_deref()?.try_into().ok() };
+        let (id, body) = match raw.y.as_str() {
+            "q" => {
+                let a = raw.a?;
+                let query = match raw.q?.as_str() {
+                    "ping" => Query::Ping,
+                    "find_node" => Query::FindNode(hash(&a.target)?),
+                    "get_peers" => Query::GetPeers(hash(&a.info_hash)?),
+                    "announce_peer" => Query::AnnouncePeer {
+                        info_hash: hash(&a.info_hash)?,
+                        port: a.port.unwrap_or(0),
+                        implied_port: a.implied_port.unwrap_or(0) != 0,
+                        token: a.token.clone()?,
+                    },
+                    q => Query::Unknown(q.to_string()),
+                };
+                (id(&a)?, Body::Query(query))
+            }
+            "r" => {
+                let r = raw.r?;
+                let response = Response {
+                    nodes: decode_nodes(r.nodes.as_deref().unwrap_or_default()),
+                    values: r
+                        .values
+                        .iter()
+                        .flatten()
+                        .filter_map(|v| decode_peer(v))
+                        .collect(),
+                    token: r.token.clone(),
+                };
+                (id(&r)?, Body::Response(response))
+            }
+            "e" => {
+                let (code, msg) = raw.e?;
+                ([0; 20], Body::Error(code, msg))
+            }
+            _ => return None,
+        };
+        Some(Self { t: raw.t, id, body })
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut raw = RawMsg {
+            t: self.t.clone(),
+            ..RawMsg::default()
+        };
+        let mut body = RawBody {
+            id: self.id.to_vec(),
+            ..RawBody::default()
+        };
+        match &self.body {
+            Body::Query(q) => {
+                raw.y = "q".to_string();
+                let method = match q {
+                    Query::Ping => "ping",
+                    Query::FindNode(target) => {
+                        body.target = Some(target.to_vec());
+                        "find_node"
+                    }
+                    Query::GetPeers(info_hash) => {
+                        body.info_hash = Some(info_hash.to_vec());
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        body.info_hash = Some(info_hash.to_vec());
+                        body.port = Some(*port);
+                        body.implied_port = Some(*implied_port as i64);
+                        body.token = Some(token.clone());
+                        "announce_peer"
+                    }
+                    Query::Unknown(q) => q,
+                };
+                raw.q = Some(method.to_string());
+                raw.a = Some(body);
+            }
+            Body::Response(r) => {
+                raw.y = "r".to_string();
+                if !r.nodes.is_empty() {
+                    body.nodes = Some(encode_nodes(&r.nodes));
+                }
+                if !r.values.is_empty() {
+                    body.values = Some(
+                        r.values
+                            .iter()
+                            .filter_map(encode_peer)
+                            .map(ByteBuf::from)
+                            .collect(),
+                    );
+                }
+                body.token = r.token.clone();
+                raw.r = Some(body);
+            }
+            Body::Error(code, msg) => {
+                raw.y = "e".to_string();
+                raw.e = Some((*code, msg.clone()));
+            }
+        }
+        to_bytes(&raw).unwrap()
+    }
+}
+
+// compact node info, ipv6 nodes are left out.
+pub fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for node in nodes {
+        if let Some(peer) = encode_peer(&node.addr) {
+            ret.extend_from_slice(&node.id);
+            ret.extend(peer);
+        }
+    }
+    ret
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<NodeInfo> {
+    bytes
+        .chunks_exact(COMPACT_NODE_LEN)
+        .filter_map(|c| {
+            Some(NodeInfo {
+                id: c[..20].try_into().unwrap(),
+                addr: decode_peer(&c[20..])?,
+            })
+        })
+        .collect()
+}
+
+// compact peer info, 4 byte ip and 2 byte port.
+pub fn encode_peer(addr: &SocketAddr) -> Option<Vec<u8>> {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            let mut ret = ip.octets().to_vec();
+            ret.extend(addr.port().to_be_bytes());
+            Some(ret)
+        }
+        IpAddr::V6(_) => None,
+    }
+}
+
+pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    if bytes.len() != 6 {
+        return None;
+    }
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    let port = u16::from_be_bytes([bytes[4], bytes[5]]);
+    Some(SocketAddr::new(IpAddr::V4(ip), port))
+}
+
+#[cfg(test)]
+mod krpc_test {
+    use super::*;
+
+    #[test]
+    fn test_spec_examples() {
+        // from BEP 5
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let msg = Krpc::parse(ping).unwrap();
+        assert_eq!(msg.t, b"aa");
+        assert_eq!(&msg.id, b"abcdefghij0123456789");
+        assert_eq!(msg.body, Body::Query(Query::Ping));
+        assert_eq!(msg.as_bytes(), ping);
+
+        let err = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let msg = Krpc::parse(err).unwrap();
+        assert_eq!(
+            msg.body,
+            Body::Error(201, "A Generic Error Ocurred".to_string())
+        );
+        assert_eq!(msg.as_bytes(), err);
+
+        let announce = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+        let msg = Krpc::parse(announce).unwrap();
+        assert_eq!(
+            msg.body,
+            Body::Query(Query::AnnouncePeer {
+                info_hash: *b"mnopqrstuvwxyz123456",
+                port: 6881,
+                implied_port: true,
+                token: b"aoeusnth".to_vec(),
+            })
+        );
+        assert_eq!(msg.as_bytes(), announce);
+    }
+
+    #[test]
+    fn test_response() {
+        let node = NodeInfo {
+            id: [7; 20],
+            addr: "127.0.0.1:6881".parse().unwrap(),
+        };
+        let response = Response {
+            nodes: vec![node],
+            values: vec!["10.0.0.1:80".parse().unwrap()],
+            token: Some(b"tok".to_vec()),
+        };
+        let msg = Krpc::response(b"xy".to_vec(), [1; 20], response);
+        assert_eq!(Krpc::parse(&msg.as_bytes()), Some(msg));
+
+        // short ids and unknown message types are dropped.
+        assert_eq!(Krpc::parse(b"d1:rd2:id3:abce1:t2:aa1:y1:re"), None);
+        assert_eq!(Krpc::parse(b"d1:t2:aa1:y1:xe"), None);
+        let unknown = b"d1:ad2:id20:abcdefghij0123456789e1:q3:foo1:t2:aa1:y1:qe";
+        assert_eq!(
+            Krpc::parse(unknown).unwrap().body,
+            Body::Query(Query::Unknown("foo".to_string()))
+        );
+    }
+}
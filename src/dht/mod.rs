@@ -0,0 +1,511 @@
+// BEP 5 mainline DHT, finding peers without a tracker.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::oneshot,
+    task::{self, JoinHandle},
+    time::{self, timeout},
+};
+
+use self::{
+    krpc::{
+        decode_nodes, encode_nodes, Body, Krpc, NodeId, NodeInfo, Query, Response, METHOD_UNKNOWN,
+        PROTOCOL_ERROR,
+    },
+    routing::{distance, RoutingTable, K},
+    store::{PeerStore, Tokens},
+};
+
+pub mod krpc;
+pub mod routing;
+pub mod store;
+
+// queries in flight per lookup.
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+// how often torrents are re-announced and stale buckets refreshed.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+pub const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    // host:port of nodes to join the network through.
+    pub bootstrap: Vec<String>,
+    // where known nodes are kept between runs.
+    pub cache: Option<PathBuf>,
+    // udp port, 0 for any.
+    pub port: u16,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bootstrap: BOOTSTRAP_NODES.iter().map(|s| s.to_string()).collect(),
+            cache: Some(PathBuf::from("dht_nodes.dat")),
+            port: 0,
+        }
+    }
+}
+
+// what a lookup found, peers and the closest nodes that gave us tokens.
+#[derive(Debug, Default)]
+struct Lookup {
+    peers: Vec<SocketAddr>,
+    tokens: Vec<(NodeInfo, Vec<u8>)>,
+}
+
+// who a query went to and who's waiting for the answer.
+type Waiting = (SocketAddr, oneshot::Sender<Krpc>);
+
+pub struct Dht {
+    socket: UdpSocket,
+    pub id: NodeId,
+    table: Mutex<RoutingTable>,
+    tokens: Mutex<Tokens>,
+    peers: Mutex<PeerStore>,
+    // by transaction id.
+    pending: Mutex<HashMap<Vec<u8>, Waiting>>,
+    next_t: AtomicU16,
+}
+
+impl Dht {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Arc<Self>> {
+        let socket = UdpSocket::bind(addr).await?;
+        let id: NodeId = rand::random();
+        Ok(Arc::new(Self {
+            socket,
+            id,
+            table: Mutex::new(RoutingTable::new(id)),
+            tokens: Mutex::new(Tokens::new(Instant::now())),
+            peers: Mutex::new(PeerStore::default()),
+            pending: Mutex::new(HashMap::new()),
+            next_t: AtomicU16::new(rand::random()),
+        }))
+    }
+
+    // binds and starts serving, bootstrapping is left to the caller.
+    pub async fn start(config: &DhtConfig) -> io::Result<(Arc<Self>, JoinHandle<()>)> {
+        let dht = Self::bind(SocketAddr::from(([0, 0, 0, 0], config.port))).await?;
+        let handle = dht.spawn();
+        Ok((dht, handle))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.table.lock().unwrap().nodes()
+    }
+
+    // answers queries and hands responses to whoever sent the query.
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<()> {
+        let dht = Arc::clone(self);
+        task::spawn(async move {
+            let mut buf = vec![0; 65536];
+            loop {
+                let (n, from) = match dht.socket.recv_from(&mut buf).await {
+                    Ok(r) => r,
+                    // i.e. icmp port unreachable from an earlier send.
+                    Err(_) => continue,
+                };
+                let msg = match Krpc::parse(&buf[..n]) {
+                    Some(m) => m,
+                    None => continue,
+                };
+                dht.handle(msg, from).await;
+            }
+        })
+    }
+
+    async fn handle(self: &Arc<Self>, msg: Krpc, from: SocketAddr) {
+        match msg.body {
+            Body::Query(query) => {
+                let reply = self.respond(msg.t, from, query);
+                let _ = self.socket.send_to(&reply.as_bytes(), from).await;
+                self.seen(msg.id, from);
+            }
+            Body::Response(_) | Body::Error(..) => {
+                let waiting = self.pending.lock().unwrap().remove(&msg.t);
+                match waiting {
+                    // answers from anyone but the node we asked are ignored.
+                    Some((addr, tx)) if addr == from => {
+                        if matches!(msg.body, Body::Response(_)) {
+                            self.seen(msg.id, from);
+                        }
+                        let _ = tx.send(msg);
+                    }
+                    Some((addr, tx)) => {
+                        self.pending.lock().unwrap().insert(msg.t, (addr, tx));
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+
+    // adds a node we heard from, pinging whoever it would replace.
+    fn seen(self: &Arc<Self>, id: NodeId, addr: SocketAddr) {
+        let ping = self
+            .table
+            .lock()
+            .unwrap()
+            .insert(NodeInfo { id, addr }, Instant::now());
+        if let Some(old) = ping {
+            let dht = Arc::clone(self);
+            task::spawn(async move {
+                if dht.ping(old.addr).await != Some(old.id) {
+                    dht.table.lock().unwrap().remove(&old.id);
+                }
+            });
+        }
+    }
+
+    fn respond(&self, t: Vec<u8>, from: SocketAddr, query: Query) -> Krpc {
+        let now = Instant::now();
+        let response = match query {
+            Query::Ping => Response::default(),
+            Query::FindNode(target) => Response {
+                nodes: self.table.lock().unwrap().closest(&target, K),
+                ..Response::default()
+            },
+            Query::GetPeers(info_hash) => {
+                let values = self.peers.lock().unwrap().get(&info_hash, now);
+                let nodes = if values.is_empty() {
+                    self.table.lock().unwrap().closest(&info_hash, K)
+                } else {
+                    vec![]
+                };
+                Response {
+                    nodes,
+                    values,
+                    token: Some(self.tokens.lock().unwrap().token(from.ip(), now)),
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !self.tokens.lock().unwrap().check(from.ip(), &token, now) {
+                    return Krpc::error(t, PROTOCOL_ERROR, "bad token");
+                }
+                let port = if implied_port { from.port() } else { port };
+                if port == 0 {
+                    return Krpc::error(t, PROTOCOL_ERROR, "bad port");
+                }
+                let peer = SocketAddr::new(from.ip(), port);
+                self.peers.lock().unwrap().announce(info_hash, peer, now);
+                Response::default()
+            }
+            Query::Unknown(_) => return Krpc::error(t, METHOD_UNKNOWN, "Method Unknown"),
+        };
+        Krpc::response(t, self.id, response)
+    }
+
+    // sends a query, None if nothing came back in time.
+    pub async fn query(&self, addr: SocketAddr, query: Query) -> Option<Krpc> {
+        let t = self
+            .next_t
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(t.clone(), (addr, tx));
+        let msg = Krpc::query(t.clone(), self.id, query);
+        let resp = match self.socket.send_to(&msg.as_bytes(), addr).await {
+            Ok(_) => timeout(QUERY_TIMEOUT, rx).await.ok().and_then(|r| r.ok()),
+            Err(_) => None,
+        };
+        self.pending.lock().unwrap().remove(&t);
+        resp
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> Option<NodeId> {
+        match self.query(addr, Query::Ping).await? {
+            Krpc {
+                id,
+                body: Body::Response(_),
+                ..
+            } => Some(id),
+            _ => None,
+        }
+    }
+
+    // joins the network through the given nodes, returns how many nodes we know after.
+    pub async fn bootstrap(self: &Arc<Self>, addrs: &[SocketAddr]) -> usize {
+        let handles: Vec<JoinHandle<Option<Krpc>>> = addrs
+            .iter()
+            .map(|addr| {
+                let dht = Arc::clone(self);
+                let addr = *addr;
+                task::spawn(async move { dht.query(addr, Query::FindNode(dht.id)).await })
+            })
+            .collect();
+        let mut seeds = vec![];
+        for (h, addr) in handles.into_iter().zip(addrs) {
+            if let Ok(Some(Krpc {
+                id,
+                body: Body::Response(r),
+                ..
+            })) = h.await
+            {
+                seeds.push(NodeInfo { id, addr: *addr });
+                seeds.extend(r.nodes);
+            }
+        }
+        self.lookup(self.id, false, seeds).await;
+        self.table.lock().unwrap().len()
+    }
+
+    // iterative lookup, asks ever closer nodes until the K closest have all answered.
+    async fn lookup(
+        self: &Arc<Self>,
+        target: NodeId,
+        get_peers: bool,
+        seeds: Vec<NodeInfo>,
+    ) -> Lookup {
+        let mut shortlist: BTreeMap<NodeId, NodeInfo> = BTreeMap::new();
+        let closest = self.table.lock().unwrap().closest(&target, K);
+        for node in closest.into_iter().chain(seeds) {
+            if node.id != self.id {
+                shortlist.insert(distance(&node.id, &target), node);
+            }
+        }
+        let mut queried = HashSet::new();
+        let mut lookup = Lookup::default();
+        loop {
+            let batch: Vec<NodeInfo> = shortlist
+                .values()
+                .take(K)
+                .filter(|n| !queried.contains(&n.id))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            let handles: Vec<JoinHandle<Option<Krpc>>> = batch
+                .iter()
+                .map(|n| {
+                    queried.insert(n.id);
+                    let dht = Arc::clone(self);
+                    let addr = n.addr;
+                    let query = if get_peers {
+                        Query::GetPeers(target)
+                    } else {
+                        Query::FindNode(target)
+                    };
+                    task::spawn(async move { dht.query(addr, query).await })
+                })
+                .collect();
+            for (h, node) in handles.into_iter().zip(batch) {
+                match h.await.ok().flatten() {
+                    Some(Krpc {
+                        id,
+                        body: Body::Response(r),
+                        ..
+                    }) => {
+                        for n in r.nodes {
+                            if n.id != self.id {
+                                shortlist.entry(distance(&n.id, &target)).or_insert(n);
+                            }
+                        }
+                        lookup.peers.extend(r.values);
+                        if let Some(token) = r.token {
+                            lookup.tokens.push((
+                                NodeInfo {
+                                    id,
+                                    addr: node.addr,
+                                },
+                                token,
+                            ));
+                        }
+                    }
+                    resp => {
+                        shortlist.remove(&distance(&node.id, &target));
+                        if resp.is_none() {
+                            self.table.lock().unwrap().fail(&node.id);
+                        }
+                    }
+                }
+            }
+        }
+        lookup.peers.sort();
+        lookup.peers.dedup();
+        lookup.tokens.sort_by_key(|(n, _)| distance(&n.id, &target));
+        lookup.tokens.truncate(K);
+        lookup
+    }
+
+    pub async fn get_peers(self: &Arc<Self>, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        self.lookup(info_hash, true, vec![]).await.peers
+    }
+
+    // finds peers and announces us, on port, to the closest nodes.
+    pub async fn announce(self: &Arc<Self>, info_hash: [u8; 20], port: u16) -> Vec<SocketAddr> {
+        let lookup = self.lookup(info_hash, true, vec![]).await;
+        let handles: Vec<JoinHandle<Option<Krpc>>> = lookup
+            .tokens
+            .into_iter()
+            .map(|(node, token)| {
+                let dht = Arc::clone(self);
+                let query = Query::AnnouncePeer {
+                    info_hash,
+                    port,
+                    implied_port: false,
+                    token,
+                };
+                task::spawn(async move { dht.query(node.addr, query).await })
+            })
+            .collect();
+        for h in handles {
+            let _ = h.await;
+        }
+        lookup.peers
+    }
+
+    // looks up a random id in every bucket that's been quiet for a while.
+    pub async fn refresh(self: &Arc<Self>) {
+        let stale = self.table.lock().unwrap().stale(Instant::now());
+        for target in stale {
+            self.lookup(target, false, vec![]).await;
+        }
+    }
+
+    // joins through config's nodes, then announces the torrent now and every
+    // ANNOUNCE_INTERVAL, peers found go to on_peers.
+    pub fn spawn_announcer<F>(
+        self: &Arc<Self>,
+        config: DhtConfig,
+        info_hashes: Vec<[u8; 20]>,
+        port: u16,
+        on_peers: F,
+    ) -> JoinHandle<()>
+    where
+        F: Fn(Vec<SocketAddr>) + Send + 'static,
+    {
+        let dht = Arc::clone(self);
+        task::spawn(async move {
+            let bootstrap = bootstrap_addrs(&config).await;
+            // fetching a magnet link's info dict may have joined already.
+            let empty = dht.table.lock().unwrap().is_empty();
+            if empty {
+                dht.bootstrap(&bootstrap).await;
+            }
+            loop {
+                for info_hash in &info_hashes {
+                    let peers = dht.announce(*info_hash, port).await;
+                    on_peers(peers);
+                }
+                time::sleep(ANNOUNCE_INTERVAL).await;
+                dht.refresh().await;
+                // lost every node, try the bootstrap nodes again.
+                let empty = dht.table.lock().unwrap().is_empty();
+                if empty {
+                    dht.bootstrap(&bootstrap).await;
+                }
+            }
+        })
+    }
+
+    pub fn save_nodes(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, encode_nodes(&self.nodes()))
+    }
+}
+
+// nodes saved by an earlier run, none if there's no cache yet.
+pub fn load_nodes(path: &Path) -> Vec<NodeInfo> {
+    match fs::read(path) {
+        Ok(bytes) => decode_nodes(&bytes),
+        Err(_) => vec![],
+    }
+}
+
+// cached nodes first, then the configured ones, unresolvable hosts are skipped.
+pub async fn bootstrap_addrs(config: &DhtConfig) -> Vec<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> = match &config.cache {
+        Some(path) => load_nodes(path).into_iter().map(|n| n.addr).collect(),
+        None => vec![],
+    };
+    for host in &config.bootstrap {
+        if let Ok(resolved) = lookup_host(host.as_str()).await {
+            addrs.extend(resolved.filter(|a| a.is_ipv4()));
+        }
+    }
+    addrs
+}
+
+#[cfg(test)]
+mod dht_test {
+    use super::*;
+
+    async fn node() -> Arc<Dht> {
+        let dht = Dht::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        dht.spawn();
+        dht
+    }
+
+    #[test]
+    fn test_loopback() {
+        tokio_test::block_on(async {
+            let mut nodes = vec![];
+            for _ in 0..6 {
+                nodes.push(node().await);
+            }
+            let first = nodes[0].local_addr().unwrap();
+            assert_eq!(nodes[1].ping(first).await, Some(nodes[0].id));
+            for n in &nodes[1..] {
+                assert!(n.bootstrap(&[first]).await > 0);
+            }
+            // everyone has met the first node, and it them.
+            assert_eq!(nodes[0].nodes().len(), nodes.len() - 1);
+
+            let info_hash = [0xab; 20];
+            assert!(nodes[1].announce(info_hash, 7000).await.is_empty());
+            let peers = nodes[5].get_peers(info_hash).await;
+            assert_eq!(peers, ["127.0.0.1:7000".parse::<SocketAddr>().unwrap()]);
+
+            // announces need a token from the node.
+            let bad = Query::AnnouncePeer {
+                info_hash,
+                port: 7001,
+                implied_port: false,
+                token: b"nope".to_vec(),
+            };
+            let resp = nodes[2].query(first, bad).await.unwrap();
+            assert!(matches!(resp.body, Body::Error(PROTOCOL_ERROR, _)));
+
+            // the node cache round trips.
+            let path =
+                std::env::temp_dir().join(format!("torrent-rs-dht-{}.nodes", std::process::id()));
+            nodes[0].save_nodes(&path).unwrap();
+            let mut cached: Vec<NodeId> = load_nodes(&path).iter().map(|n| n.id).collect();
+            let mut known: Vec<NodeId> = nodes[0].nodes().iter().map(|n| n.id).collect();
+            cached.sort();
+            known.sort();
+            assert_eq!(cached, known);
+            fs::remove_file(&path).unwrap();
+        });
+    }
+}
@@ -0,0 +1,252 @@
+// kademlia routing table, one k-bucket per bit of xor distance from our id.
+
+use std::time::{Duration, Instant};
+
+use super::krpc::{NodeId, NodeInfo};
+
+// nodes per bucket.
+pub const K: usize = 8;
+// nodes not heard from in this long are questionable.
+pub const QUESTIONABLE: Duration = Duration::from_secs(15 * 60);
+// nodes that failed to respond this many times in a row are bad.
+const MAX_FAILS: u8 = 3;
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut ret = [0; 20];
+    for i in 0..20 {
+        ret[i] = a[i] ^ b[i];
+    }
+    ret
+}
+
+// the bucket a node falls in, the number of leading bits it shares with us.
+fn bucket_index(own: &NodeId, id: &NodeId) -> Option<usize> {
+    let d = distance(own, id);
+    let zeros = d
+        .iter()
+        .position(|b| *b != 0)
+        .map(|i| i * 8 + d[i].leading_zeros() as usize)?;
+    Some(zeros)
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub info: NodeInfo,
+    pub last_seen: Instant,
+    fails: u8,
+}
+
+impl Node {
+    pub fn is_bad(&self) -> bool {
+        self.fails >= MAX_FAILS
+    }
+
+    pub fn is_questionable(&self, now: Instant) -> bool {
+        now.duration_since(self.last_seen) > QUESTIONABLE
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Bucket {
+    // least recently seen first.
+    nodes: Vec<Node>,
+    changed: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct RoutingTable {
+    pub id: NodeId,
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Bucket::default(); 160],
+        }
+    }
+
+    // records a node that just sent us a message, if its bucket is full returns
+    // a questionable node to ping, which is dropped if it doesn't answer.
+    pub fn insert(&mut self, info: NodeInfo, now: Instant) -> Option<NodeInfo> {
+        let index = bucket_index(&self.id, &info.id)?;
+        let bucket = &mut self.buckets[index];
+        if let Some(i) = bucket.nodes.iter().position(|n| n.info.id == info.id) {
+            let mut node = bucket.nodes.remove(i);
+            node.info.addr = info.addr;
+            node.last_seen = now;
+            node.fails = 0;
+            bucket.nodes.push(node);
+            bucket.changed = Some(now);
+            return None;
+        }
+        if bucket.nodes.len() >= K {
+            match bucket.nodes.iter().position(|n| n.is_bad()) {
+                Some(i) => {
+                    bucket.nodes.remove(i);
+                }
+                // good nodes are never evicted, questionable ones once proven bad.
+                None => {
+                    return bucket
+                        .nodes
+                        .iter()
+                        .find(|n| n.is_questionable(now))
+                        .map(|n| n.info);
+                }
+            }
+        }
+        bucket.nodes.push(Node {
+            info,
+            last_seen: now,
+            fails: 0,
+        });
+        bucket.changed = Some(now);
+        None
+    }
+
+    // a query to the node timed out.
+    pub fn fail(&mut self, id: &NodeId) {
+        if let Some(index) = bucket_index(&self.id, id) {
+            if let Some(n) = self.buckets[index]
+                .nodes
+                .iter_mut()
+                .find(|n| n.info.id == *id)
+            {
+                n.fails = n.fails.saturating_add(1);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = bucket_index(&self.id, id) {
+            self.buckets[index].nodes.retain(|n| n.info.id != *id);
+        }
+    }
+
+    // the n closest nodes to target that aren't bad, closest first.
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flat_map(|b| &b.nodes)
+            .filter(|n| !n.is_bad())
+            .map(|n| n.info)
+            .collect();
+        nodes.sort_by_key(|n| distance(&n.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flat_map(|b| &b.nodes)
+            .filter(|n| !n.is_bad())
+            .map(|n| n.info)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.nodes.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // a random id in each non empty bucket that hasn't changed in a while.
+    pub fn stale(&self, now: Instant) -> Vec<NodeId> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| {
+                b.changed
+                    .is_some_and(|c| now.duration_since(c) > QUESTIONABLE)
+            })
+            .map(|(i, _)| random_in_bucket(&self.id, i))
+            .collect()
+    }
+}
+
+// an id sharing exactly index leading bits with own.
+pub fn random_in_bucket(own: &NodeId, index: usize) -> NodeId {
+    let mut id: NodeId = rand::random();
+    let (byte, bit) = (index / 8, index % 8);
+    id[..byte].copy_from_slice(&own[..byte]);
+    let keep = !(0xff_u8 >> bit);
+    let flip = 0x80_u8 >> bit;
+    id[byte] = (own[byte] & keep) | (!own[byte] & flip) | (id[byte] & !(keep | flip));
+    id
+}
+
+#[cfg(test)]
+mod routing_test {
+    use super::*;
+
+    fn node(id: NodeId, port: u16) -> NodeInfo {
+        NodeInfo {
+            id,
+            addr: ([127, 0, 0, 1], port).into(),
+        }
+    }
+
+    #[test]
+    fn test_buckets() {
+        let own = [0; 20];
+        let mut table = RoutingTable::new(own);
+        let now = Instant::now();
+
+        // ourselves never go in.
+        assert_eq!(table.insert(node(own, 1), now), None);
+        assert!(table.is_empty());
+
+        // all of these share no leading bits with us, so the same bucket.
+        for i in 0..K as u8 {
+            let mut id = [0; 20];
+            id[0] = 0x80 | i;
+            table.insert(node(id, i as u16), now);
+        }
+        assert_eq!(table.len(), K);
+        let mut extra = [0; 20];
+        extra[0] = 0xff;
+        // full of good nodes, the newcomer is dropped.
+        assert_eq!(table.insert(node(extra, 99), now), None);
+        assert_eq!(table.len(), K);
+        // questionable ones are offered up for a ping.
+        let later = now + QUESTIONABLE + Duration::from_secs(1);
+        let ping = table.insert(node(extra, 99), later).unwrap();
+        assert_eq!(ping.id[0], 0x80);
+        for _ in 0..MAX_FAILS {
+            table.fail(&ping.id);
+        }
+        assert_eq!(table.insert(node(extra, 99), later), None);
+        assert!(table.nodes().iter().any(|n| n.id == extra));
+        assert!(!table.nodes().iter().any(|n| n.id == ping.id));
+    }
+
+    #[test]
+    fn test_closest() {
+        let mut table = RoutingTable::new([0; 20]);
+        let now = Instant::now();
+        for i in 1..=20_u8 {
+            let mut id = [0; 20];
+            id[19] = i;
+            table.insert(node(id, i as u16), now);
+        }
+        let mut target = [0; 20];
+        target[19] = 6;
+        let closest = table.closest(&target, 3);
+        let ids: Vec<u8> = closest.iter().map(|n| n.id[19]).collect();
+        assert_eq!(ids, [6, 7, 4]);
+    }
+
+    #[test]
+    fn test_random_in_bucket() {
+        let own: NodeId = rand::random();
+        for index in [0, 7, 8, 100, 159] {
+            let id = random_in_bucket(&own, index);
+            assert_eq!(bucket_index(&own, &id), Some(index));
+        }
+    }
+}
@@ -0,0 +1,117 @@
+// announce tokens we hand out and the peers announced to us.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use sha1::{Digest, Sha1};
+
+// secrets rotate this often, tokens from the previous secret are still accepted.
+const TOKEN_ROTATE: Duration = Duration::from_secs(5 * 60);
+// announced peers are forgotten after this long.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+// peers kept, and returned, per info hash.
+const MAX_PEERS: usize = 100;
+
+#[derive(Debug)]
+pub struct Tokens {
+    secret: [u8; 20],
+    prev: [u8; 20],
+    rotated: Instant,
+}
+
+impl Tokens {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            secret: rand::random(),
+            prev: rand::random(),
+            rotated: now,
+        }
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        if now.duration_since(self.rotated) >= TOKEN_ROTATE {
+            self.prev = self.secret;
+            self.secret = rand::random();
+            self.rotated = now;
+        }
+    }
+
+    // the token only the node at ip can announce with.
+    pub fn token(&mut self, ip: IpAddr, now: Instant) -> Vec<u8> {
+        self.rotate(now);
+        make_token(&self.secret, ip)
+    }
+
+    pub fn check(&mut self, ip: IpAddr, token: &[u8], now: Instant) -> bool {
+        self.rotate(now);
+        token == make_token(&self.secret, ip) || token == make_token(&self.prev, ip)
+    }
+}
+
+fn make_token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.finalize()[..8].to_vec()
+}
+
+#[derive(Debug, Default)]
+pub struct PeerStore {
+    peers: HashMap<[u8; 20], HashMap<SocketAddr, Instant>>,
+}
+
+impl PeerStore {
+    pub fn announce(&mut self, info_hash: [u8; 20], peer: SocketAddr, now: Instant) {
+        let peers = self.peers.entry(info_hash).or_default();
+        peers.retain(|_, t| now.duration_since(*t) < PEER_TTL);
+        if peers.len() < MAX_PEERS || peers.contains_key(&peer) {
+            peers.insert(peer, now);
+        }
+    }
+
+    pub fn get(&self, info_hash: &[u8; 20], now: Instant) -> Vec<SocketAddr> {
+        match self.peers.get(info_hash) {
+            Some(peers) => peers
+                .iter()
+                .filter(|(_, t)| now.duration_since(**t) < PEER_TTL)
+                .map(|(a, _)| *a)
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod store_test {
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        let now = Instant::now();
+        let mut tokens = Tokens::new(now);
+        let ip: IpAddr = [127, 0, 0, 1].into();
+        let token = tokens.token(ip, now);
+        assert!(tokens.check(ip, &token, now));
+        assert!(!tokens.check([127, 0, 0, 2].into(), &token, now));
+        // still good one rotation later, not two.
+        assert!(tokens.check(ip, &token, now + TOKEN_ROTATE));
+        assert!(!tokens.check(ip, &token, now + TOKEN_ROTATE * 2));
+    }
+
+    #[test]
+    fn test_peers() {
+        let now = Instant::now();
+        let mut store = PeerStore::default();
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        store.announce([1; 20], peer, now);
+        assert_eq!(store.get(&[1; 20], now), [peer]);
+        assert!(store.get(&[2; 20], now).is_empty());
+        assert!(store.get(&[1; 20], now + PEER_TTL).is_empty());
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
-    bencode::Item,
     hash::Hasher,
+    metainfo::Metainfo,
     tcp_bt::msg::{
         bytes::PIECE,
         structs::{Header, Piece},
@@ -8,7 +8,7 @@ use crate::{
     },
     torrent::Client,
 };
-use std::{collections::BTreeMap, io::SeekFrom, ops::Deref, path::Path, str::from_utf8, sync::Arc};
+use std::{io::SeekFrom, ops::Deref, path::PathBuf, sync::Arc};
 use tokio::{
     fs::{create_dir_all, File, OpenOptions},
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
@@ -18,81 +18,46 @@ use tokio::{
 
 #[derive(Debug, Clone)]
 pub struct FileSize {
-    file: Arc<TokioMutex<File>>,
+    // none for padding, which reads as zeros and is never written.
+    file: Option<Arc<TokioMutex<File>>>,
     len: usize,
 }
 
-pub async fn parse_file(info: &BTreeMap<Vec<u8>, Item>) -> (Arc<Vec<FileSize>>, usize) {
-    // single file -> only single file owns length field.
-    if let Some(s) = info.get("length".as_bytes()) {
-        // file length
-        let file_length: usize = s.get_integer();
-        // name of the file
-        let file_name = info.get("name".as_bytes()).unwrap().get_string();
-        // create file and return
-        let path = Path::new(std::str::from_utf8(&file_name).unwrap());
-        let dest = Arc::new(TokioMutex::new(
+pub async fn parse_file(meta: &Metainfo) -> (Arc<Vec<FileSize>>, usize) {
+    let mut ret: Vec<FileSize> = vec![];
+    // single file torrents are one file named after the torrent,
+    // multiple files live under a folder of that name.
+    for f in meta.files() {
+        if f.pad {
+            ret.push(FileSize {
+                file: None,
+                len: f.length,
+            });
+            continue;
+        }
+        let mut file_path = PathBuf::from(".");
+        file_path.extend(&f.path);
+
+        // creat parents and file
+        if let Some(parent) = file_path.parent() {
+            create_dir_all(parent).await.unwrap();
+        }
+        let file = Some(Arc::new(TokioMutex::new(
             OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create(true)
-                .open(path)
+                .truncate(false)
+                .open(&file_path)
                 .await
                 .unwrap(),
-        ));
-
-        let file_size = FileSize {
-            file: dest,
-            len: file_length,
-        };
-
-        (Arc::new(vec![file_size]), file_length)
-    } else {
-        // multiple files
-        // get parent folder and file dicts.
-        let name = info.get("name".as_bytes()).unwrap().get_string();
-        let files = info.get("files".as_bytes()).unwrap().get_list();
-        let mut ret: Vec<FileSize> = vec![];
-        // for each dict
-        for f in files {
-            let dict = f.get_dict();
-            // get file length
-            let len = dict.get("length".as_bytes()).unwrap().get_integer();
-            // get file path
-            let mut path_list = dict.get("path".as_bytes()).unwrap().get_list();
-
-            // get end file name
-            let end_file = path_list.pop().unwrap().get_string();
-            let file_name = from_utf8(&end_file).unwrap();
-            // parent folders to the filename
-            let mut base = "./".to_string() + from_utf8(&name).unwrap();
-            for folder in path_list {
-                let folder_name = "/".to_string() + from_utf8(&folder.get_string()).unwrap();
-                base.push_str(&folder_name)
-            }
-
-            // crreat parents and file
-            create_dir_all(base.clone()).await.unwrap();
-            let full_path = base + "/" + file_name;
-            let file_path = Path::new(&full_path);
-            let file = Arc::new(TokioMutex::new(
-                OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .open(file_path)
-                    .await
-                    .unwrap(),
-            ));
-            ret.push(FileSize { file, len })
-        }
-        // get total length from each file
-        let mut total_len: usize = 0;
-        for filesize in &ret {
-            total_len += filesize.len;
-        }
-        (Arc::new(ret), total_len)
+        )));
+        ret.push(FileSize {
+            file,
+            len: f.length,
+        })
     }
+    (Arc::new(ret), meta.total_length())
 }
 
 pub async fn write_subpiece(piece: &Piece, piece_len: usize, files: &Arc<Vec<FileSize>>) {
@@ -109,8 +74,8 @@ pub async fn write_subpiece(piece: &Piece, piece_len: usize, files: &Arc<Vec<Fil
 
         if next_file > 0 {
             // write the rest onto the next file
-            {
-                let mut f = filesize.file.lock().await;
+            if let Some(file) = &filesize.file {
+                let mut f = file.lock().await;
                 f.seek(SeekFrom::Start(0)).await.unwrap();
                 f.write_all(&piece.data[(end - start)..]).await.unwrap();
             }
@@ -120,8 +85,8 @@ pub async fn write_subpiece(piece: &Piece, piece_len: usize, files: &Arc<Vec<Fil
             next_file = (end - filesize.len) as u64;
             end = filesize.len;
         }
-        {
-            let mut f = filesize.file.lock().await;
+        if let Some(file) = &filesize.file {
+            let mut f = file.lock().await;
             f.seek(SeekFrom::Start(start as u64)).await.unwrap();
             f.write_all(&piece.data[..(end - start)]).await.unwrap();
         }
@@ -131,7 +96,6 @@ pub async fn write_subpiece(piece: &Piece, piece_len: usize, files: &Arc<Vec<Fil
     }
 }
 
-
 // reads a subpiece mapped from it's file(s)
 pub async fn read_subpiece(index: usize, offset: usize, torrent: &Arc<Client>) -> Option<Piece> {
     let mut start = (index * torrent.piece_len) + offset;
@@ -149,8 +113,8 @@ pub async fn read_subpiece(index: usize, offset: usize, torrent: &Arc<Client>) -
         if next_file > 0 {
             // read the rest from the next file
             let mut buf: Vec<u8> = vec![0; next_file as usize];
-            {
-                let mut f = filesize.file.lock().await;
+            if let Some(file) = &filesize.file {
+                let mut f = file.lock().await;
                 f.seek(SeekFrom::Start(0)).await.unwrap();
                 f.read_exact(&mut buf).await.ok()?;
             }
@@ -163,8 +127,8 @@ pub async fn read_subpiece(index: usize, offset: usize, torrent: &Arc<Client>) -
         }
 
         piece_buf = vec![0; end - start];
-        {
-            let mut f = filesize.file.lock().await;
+        if let Some(file) = &filesize.file {
+            let mut f = file.lock().await;
             f.seek(SeekFrom::Start(start as u64)).await.unwrap();
             f.read_exact(&mut piece_buf).await.ok()?;
         }
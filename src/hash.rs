@@ -8,6 +8,7 @@ use std::{
 };
 
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use tokio::runtime::Handle;
 
 use crate::{
@@ -16,6 +17,7 @@ use crate::{
         ByteField,
     },
     file::write_subpiece,
+    metainfo::PieceV2,
     tcp_bt::{connect::Connector, msg::structs::Piece},
     torrent::Client,
 };
@@ -39,83 +41,45 @@ impl Hasher {
     }
 }
 
-// spawns hashing threads
-pub fn spawn_hash_write(
-    hasher: &Arc<Hasher>,
-    field: &Arc<Mutex<ByteField>>,
-    client: &Arc<Client>,
-    connecter: &Arc<Connector>,
-    handle: Handle,
-    threads: usize,
-) -> Vec<JoinHandle<()>> {
+impl Default for Hasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// blocks until a piece is queued, None once brk is set.
+fn next_piece(hasher: &Hasher) -> Option<Vec<Piece>> {
+    let piece = {
+        let mut guard = hasher
+            .loops
+            .wait_while(hasher.queue.lock().unwrap(), |q| {
+                !hasher.brk.load(Ordering::Relaxed) && q.is_empty()
+            })
+            .unwrap();
+        if hasher.brk.load(Ordering::Relaxed) {
+            return None;
+        }
+        guard.pop_front()?
+    };
+    hasher.empty.notify_all();
+    Some(piece)
+}
+
+// spawns hashing threads that run work on each queued piece until brk is set.
+fn spawn_hash_threads<F>(hasher: &Arc<Hasher>, threads: usize, work: F) -> Vec<JoinHandle<()>>
+where
+    F: Fn(Vec<Piece>) + Send + Sync + 'static,
+{
+    let work = Arc::new(work);
     let mut handles = Vec::new();
     for i in 0..threads {
         let hasher = Arc::clone(hasher);
-        let piece_field = Arc::clone(&field);
-        let client = Arc::clone(client);
-        let files = Arc::clone(&client.files);
-        let connecter = Arc::clone(connecter);
-        let handle = handle.clone();
-
+        let work = Arc::clone(&work);
         let builder = thread::Builder::new().name(format!("Hasher{}", i));
         let handle = builder
             .spawn(move || {
-                loop {
-                    let mut piece;
-                    {
-                        let mut guard = hasher
-                            .loops
-                            .wait_while(hasher.queue.lock().unwrap(), |q| {
-                                if hasher.brk.load(Ordering::Relaxed) {
-                                    return false;
-                                }
-                                q.is_empty()
-                            })
-                            .unwrap();
-                        if hasher.brk.load(Ordering::Relaxed) {
-                            break;
-                        }
-                        piece = match guard.pop_front() {
-                            Some(t) => t,
-                            None => break,
-                        }
-                    }
-                    hasher.empty.notify_all();
-                    let index = piece[0].index as usize;
-                    let mut flat_piece = Vec::with_capacity(client.piece_len);
-                    piece.sort_by_key(|x| x.begin);
-                    for s in &piece {
-                        flat_piece.extend_from_slice(&s.data); // assumes ordered by begin.
-                    }
-
-                    let mut hasher = Sha1::new();
-                    hasher.update(flat_piece);
-                    let piece_hash = hasher.finalize().to_vec();
-
-                    if piece_hash
-                        .iter()
-                        .zip(&client.hashes[index])
-                        .filter(|(a, b)| *a == *b)
-                        .count()
-                        != 20
-                    {
-                        {
-                            // unreserve piece
-                            let mut pf = piece_field.lock().unwrap();
-                            pf.arr[index] = EMPTY;
-                            // notify waiting connections
-                            connecter.piece.notify_one();
-                        }
-                        continue;
-                    }
-                    for s in &piece {
-                        handle.block_on(write_subpiece(s, client.piece_len, &files));
-                    }
-                    {
-                        // critial section
-                        let mut pf = piece_field.lock().unwrap();
-                        pf.arr[index] = COMPLETE;
-                    }
+                while let Some(piece) = next_piece(&hasher) {
+                    work(piece);
                 }
             })
             .unwrap();
@@ -124,6 +88,85 @@ pub fn spawn_hash_write(
     handles
 }
 
+// spawns hashing threads that verify downloaded pieces and write them to disk.
+pub fn spawn_hash_write(
+    hasher: &Arc<Hasher>,
+    field: &Arc<Mutex<ByteField>>,
+    client: &Arc<Client>,
+    connecter: &Arc<Connector>,
+    handle: Handle,
+    threads: usize,
+) -> Vec<JoinHandle<()>> {
+    let piece_field = Arc::clone(field);
+    let client = Arc::clone(client);
+    let files = Arc::clone(&client.files);
+    let connecter = Arc::clone(connecter);
+    spawn_hash_threads(hasher, threads, move |mut piece| {
+        let index = piece[0].index as usize;
+        let mut flat_piece = Vec::with_capacity(client.piece_len);
+        piece.sort_by_key(|x| x.begin);
+        for s in &piece {
+            flat_piece.extend_from_slice(&s.data); // assumes ordered by begin.
+        }
+
+        // fails closed, a piece must pass every hash set there is, both for
+        // hybrid torrents, and is never written without one.
+        let (has_v1, has_v2) = (!client.hashes.is_empty(), !client.hashes_v2.is_empty());
+        let mut valid = has_v1 || has_v2;
+        if valid && has_v1 {
+            let mut hasher = Sha1::new();
+            hasher.update(&flat_piece);
+            let piece_hash = hasher.finalize().to_vec();
+            valid = client.hashes.get(index) == Some(&piece_hash);
+        }
+        if valid && has_v2 {
+            valid = client
+                .hashes_v2
+                .get(index)
+                .is_some_and(|h| verify_piece_v2(&flat_piece, h));
+        }
+
+        if !valid {
+            // unreserve piece
+            let mut pf = piece_field.lock().unwrap();
+            pf.arr[index] = EMPTY;
+            // notify waiting connections
+            connecter.piece.notify_one();
+            return;
+        }
+        for s in &piece {
+            handle.block_on(write_subpiece(s, client.piece_len, &files));
+        }
+        {
+            // critial section
+            let mut pf = piece_field.lock().unwrap();
+            pf.arr[index] = COMPLETE;
+        }
+    })
+}
+
+// spawns hashing threads that only record each piece's hash, used when creating torrents.
+pub fn spawn_hash_pieces(
+    hasher: &Arc<Hasher>,
+    hashes: &Arc<Mutex<Vec<[u8; 20]>>>,
+    threads: usize,
+) -> Vec<JoinHandle<()>> {
+    let hashes = Arc::clone(hashes);
+    spawn_hash_threads(hasher, threads, move |piece| {
+        let index = piece[0].index as usize;
+        let mut sha = Sha1::new();
+        for s in &piece {
+            sha.update(&s.data);
+        }
+        let piece_hash: [u8; 20] = sha.finalize().into();
+        {
+            // critial section
+            let mut h = hashes.lock().unwrap();
+            h[index] = piece_hash;
+        }
+    })
+}
+
 // splits hashes from 1D rasterized to 2D.
 pub fn split_hashes(hashes: &[u8]) -> Vec<Vec<u8>> {
     let num_pieces = hashes.len() / 20;
@@ -133,3 +176,44 @@ pub fn split_hashes(hashes: &[u8]) -> Vec<Vec<u8>> {
     }
     split_hashes
 }
+
+// BEP 52 merkle trees are built over 16 KiB blocks.
+pub const BLOCK_LEN: usize = 0x4000;
+
+// SHA-256 of each block, the last one may be shorter.
+pub fn block_hashes(data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(BLOCK_LEN)
+        .map(|b| Sha256::digest(b).into())
+        .collect()
+}
+
+// root of a tree whose bottom layer is `layer` padded with `pad` out to `width` nodes,
+// width being a power of two.
+pub fn merkle_root(mut layer: Vec<[u8; 32]>, width: usize, pad: [u8; 32]) -> [u8; 32] {
+    layer.resize(width.max(1), pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                let mut sha = Sha256::new();
+                sha.update(pair[0]);
+                sha.update(pair[1]);
+                sha.finalize().into()
+            })
+            .collect();
+    }
+    layer[0]
+}
+
+// root of a subtree of `leaves` zeroed leaves, used to pad piece layers.
+pub fn pad_hash(leaves: usize) -> [u8; 32] {
+    merkle_root(vec![], leaves, [0; 32])
+}
+
+// checks a piece's data, padding files stripped, against its v2 subtree root.
+pub fn verify_piece_v2(data: &[u8], piece: &PieceV2) -> bool {
+    if data.len() < piece.len {
+        return false;
+    }
+    merkle_root(block_hashes(&data[..piece.len]), piece.width, [0; 32]) == piece.hash
+}
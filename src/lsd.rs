@@ -0,0 +1,259 @@
+// BEP 14 local service discovery, finding peers on the same network over multicast.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    task::{self, JoinHandle},
+    time,
+};
+
+use crate::magnet::decode_hex;
+
+pub const LSD_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_PORT: u16 = 6771;
+// how often every torrent is announced.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// a torrent is never announced more often than this.
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Search {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    // set by the sender to spot its own announces.
+    pub cookie: Option<String>,
+}
+
+impl Search {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut msg = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}:{}\r\nPort: {}\r\n",
+            LSD_GROUP, LSD_PORT, self.port
+        );
+        for hash in &self.info_hashes {
+            msg.push_str("Infohash: ");
+            for b in hash {
+                write!(msg, "{:02x}", b).unwrap();
+            }
+            msg.push_str("\r\n");
+        }
+        if let Some(cookie) = &self.cookie {
+            write!(msg, "cookie: {}\r\n", cookie).unwrap();
+        }
+        msg.push_str("\r\n\r\n");
+        msg.into_bytes()
+    }
+
+    // header names are case insensitive, malformed info hashes are skipped.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let msg = std::str::from_utf8(bytes).ok()?;
+        let mut lines = msg.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+        let mut search = Search {
+            port: 0,
+            info_hashes: vec![],
+            cookie: None,
+        };
+        for line in lines {
+            let (key, value) = match line.split_once(':') {
+                Some((k, v)) => (k.trim().to_ascii_lowercase(), v.trim()),
+                None => continue,
+            };
+            match key.as_str() {
+                "port" => search.port = value.parse().ok()?,
+                "infohash" => {
+                    if let Some(hash) = decode_hex(value).and_then(|h| h.try_into().ok()) {
+                        search.info_hashes.push(hash);
+                    }
+                }
+                "cookie" => search.cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        if search.port == 0 || search.info_hashes.is_empty() {
+            return None;
+        }
+        Some(search)
+    }
+}
+
+pub struct Lsd {
+    socket: UdpSocket,
+    // where announces go, the multicast group outside of tests.
+    target: SocketAddr,
+    cookie: String,
+    // when each torrent was last announced.
+    sent: Mutex<HashMap<[u8; 20], Instant>>,
+}
+
+impl Lsd {
+    // joins the multicast group, the port is shared with other clients on this host.
+    pub fn bind() -> io::Result<Arc<Self>> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())?;
+        socket.join_multicast_v4(&LSD_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        Ok(Self::with_socket(socket, (LSD_GROUP, LSD_PORT).into()))
+    }
+
+    pub fn with_socket(socket: UdpSocket, target: SocketAddr) -> Arc<Self> {
+        Arc::new(Self {
+            socket,
+            target,
+            cookie: format!("{:08x}", rand::random::<u32>()),
+            sent: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // the torrents that weren't announced in the last MIN_INTERVAL, marked as sent.
+    fn due(&self, info_hashes: &[[u8; 20]], now: Instant) -> Vec<[u8; 20]> {
+        let mut sent = self.sent.lock().unwrap();
+        info_hashes
+            .iter()
+            .filter(|h| {
+                let due = sent
+                    .get(*h)
+                    .is_none_or(|t| now.duration_since(*t) >= MIN_INTERVAL);
+                if due {
+                    sent.insert(**h, now);
+                }
+                due
+            })
+            .copied()
+            .collect()
+    }
+
+    // announces the torrents that are due, false if none were.
+    pub async fn announce(&self, info_hashes: &[[u8; 20]], port: u16) -> io::Result<bool> {
+        let due = self.due(info_hashes, Instant::now());
+        if due.is_empty() {
+            return Ok(false);
+        }
+        let search = Search {
+            port,
+            info_hashes: due,
+            cookie: Some(self.cookie.clone()),
+        };
+        self.socket.send_to(&search.as_bytes(), self.target).await?;
+        Ok(true)
+    }
+
+    // peers announcing any of info_hashes go to on_peers, our own announces are ignored.
+    pub fn spawn_listener<F>(
+        self: &Arc<Self>,
+        info_hashes: Vec<[u8; 20]>,
+        on_peers: F,
+    ) -> JoinHandle<()>
+    where
+        F: Fn(Vec<SocketAddr>) + Send + 'static,
+    {
+        let lsd = Arc::clone(self);
+        task::spawn(async move {
+            let mut buf = vec![0; 1500];
+            loop {
+                let (n, from) = match lsd.socket.recv_from(&mut buf).await {
+                    Ok(r) => r,
+                    Err(_) => continue,
+                };
+                let search = match Search::parse(&buf[..n]) {
+                    Some(s) => s,
+                    None => continue,
+                };
+                if search.cookie.as_deref() == Some(lsd.cookie.as_str()) {
+                    continue;
+                }
+                if search.info_hashes.iter().any(|h| info_hashes.contains(h)) {
+                    on_peers(vec![SocketAddr::new(from.ip(), search.port)]);
+                }
+            }
+        })
+    }
+
+    pub fn spawn_announcer(
+        self: &Arc<Self>,
+        info_hashes: Vec<[u8; 20]>,
+        port: u16,
+    ) -> JoinHandle<()> {
+        let lsd = Arc::clone(self);
+        task::spawn(async move {
+            loop {
+                if let Err(e) = lsd.announce(&info_hashes, port).await {
+                    eprintln!("lsd: {}", e);
+                }
+                time::sleep(ANNOUNCE_INTERVAL).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod lsd_test {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        let search = Search {
+            port: 6881,
+            info_hashes: vec![[0xab; 20], [0x01; 20]],
+            cookie: Some("c00k1e".to_string()),
+        };
+        let bytes = search.as_bytes();
+        assert!(bytes.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+        assert_eq!(Search::parse(&bytes), Some(search));
+
+        let msg = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHOST: x\r\nport: 1234\r\ninfohash: {}\r\n\r\n\r\n",
+            "AB".repeat(20)
+        );
+        let search = Search::parse(msg.as_bytes()).unwrap();
+        assert_eq!(search.port, 1234);
+        assert_eq!(search.info_hashes, [[0xab; 20]]);
+        assert_eq!(search.cookie, None);
+
+        assert_eq!(Search::parse(b"M-SEARCH * HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(
+            Search::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_loopback() {
+        tokio_test::block_on(async {
+            let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let b_addr = b.local_addr().unwrap();
+            let a = Lsd::with_socket(UdpSocket::bind("127.0.0.1:0").await.unwrap(), b_addr);
+            let b = Lsd::with_socket(b, a.socket.local_addr().unwrap());
+
+            let (tx, rx) = async_channel::unbounded();
+            let listener = b.spawn_listener(vec![[1; 20]], move |peers| {
+                tx.try_send(peers).unwrap();
+            });
+            assert!(a.announce(&[[1; 20], [2; 20]], 7000).await.unwrap());
+            let peers = rx.recv().await.unwrap();
+            assert_eq!(peers, ["127.0.0.1:7000".parse::<SocketAddr>().unwrap()]);
+
+            // rate limited per torrent.
+            assert!(!a.announce(&[[1; 20]], 7000).await.unwrap());
+            assert!(a.announce(&[[1; 20], [3; 20]], 7000).await.unwrap());
+            let now = Instant::now();
+            assert!(a.due(&[[1; 20], [3; 20]], now).is_empty());
+            assert_eq!(a.due(&[[1; 20]], now + MIN_INTERVAL), [[1; 20]]);
+            listener.abort();
+        });
+    }
+}
@@ -0,0 +1,268 @@
+// magnet uri parsing, https://www.bittorrent.org/beps/bep_0009.html#magnet-uri-format
+
+use std::{error::Error, fmt::Display, net::SocketAddr};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MagnetError {
+    // doesn't start with `magnet:?`.
+    NotMagnet,
+    // no `xt` with a btih or btmh urn.
+    MissingInfoHash,
+    InvalidInfoHash(String),
+    // a parameter that couldn't be decoded, the key.
+    InvalidParam(String),
+}
+
+impl Display for MagnetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MagnetError::NotMagnet => write!(f, "not a magnet link"),
+            MagnetError::MissingInfoHash => write!(f, "magnet link has no info hash"),
+            MagnetError::InvalidInfoHash(h) => write!(f, "invalid info hash {}", h),
+            MagnetError::InvalidParam(k) => write!(f, "invalid magnet parameter `{}`", k),
+        }
+    }
+}
+
+impl Error for MagnetError {}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Magnet {
+    // btih, or the truncated btmh for v2 only links.
+    pub info_hash: [u8; 20],
+    // BEP 52 btmh, sha2-256 multihash.
+    pub info_hash_v2: Option<[u8; 32]>,
+    // dn
+    pub name: Option<String>,
+    // tr
+    pub trackers: Vec<String>,
+    // ws
+    pub web_seeds: Vec<String>,
+    // x.pe with a literal ip.
+    pub peers: Vec<SocketAddr>,
+    // x.pe with a hostname, host:port, resolved by the client rather than here.
+    pub peer_hosts: Vec<String>,
+    // so, file indexes to download.
+    pub select_only: Option<Vec<usize>>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Self, MagnetError> {
+        let query = match uri.strip_prefix("magnet:?") {
+            Some(q) => q,
+            None => return Err(MagnetError::NotMagnet),
+        };
+        let mut magnet = Magnet::default();
+        let mut btih = None;
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            // repeated keys may be numbered, i.e. tr.1
+            let key = match key.rsplit_once('.') {
+                Some((k, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => k,
+                _ => key,
+            };
+            // the display name may be form encoded, a `+` there is a space, not a %2B.
+            let value = match key {
+                "dn" => percent_decode(&value.replace('+', " ")),
+                _ => percent_decode(value),
+            };
+            let value = match value {
+                Some(v) => v,
+                None => return Err(MagnetError::InvalidParam(key.to_string())),
+            };
+            match key {
+                "xt" => {
+                    if let Some(h) = value.strip_prefix("urn:btih:") {
+                        btih = Some(parse_btih(h)?);
+                    } else if let Some(h) = value.strip_prefix("urn:btmh:") {
+                        magnet.info_hash_v2 = Some(parse_btmh(h)?);
+                    }
+                }
+                "dn" => magnet.name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                "ws" => magnet.web_seeds.push(value),
+                "x.pe" => match value.parse() {
+                    Ok(addr) => magnet.peers.push(addr),
+                    Err(_) if is_host_port(&value) => magnet.peer_hosts.push(value),
+                    Err(_) => {}
+                },
+                "so" => match parse_select_only(&value) {
+                    Some(s) => magnet.select_only = Some(s),
+                    None => return Err(MagnetError::InvalidParam(key.to_string())),
+                },
+                // unknown parameters are ignored.
+                _ => {}
+            }
+        }
+        magnet.info_hash = match (btih, magnet.info_hash_v2) {
+            (Some(h), _) => h,
+            (None, Some(v2)) => v2[..20].try_into().unwrap(),
+            (None, None) => return Err(MagnetError::MissingInfoHash),
+        };
+        Ok(magnet)
+    }
+}
+
+// 40 hex digits or 32 base32 characters.
+fn parse_btih(hash: &str) -> Result<[u8; 20], MagnetError> {
+    let bytes = match hash.len() {
+        40 => decode_hex(hash),
+        32 => decode_base32(hash),
+        _ => None,
+    };
+    match bytes {
+        Some(b) => Ok(b.try_into().unwrap()),
+        None => Err(MagnetError::InvalidInfoHash(hash.to_string())),
+    }
+}
+
+// hex multihash, 0x12 sha2-256 and 0x20 bytes long, then the hash.
+fn parse_btmh(hash: &str) -> Result<[u8; 32], MagnetError> {
+    match decode_hex(hash) {
+        Some(b) if b.len() == 34 && b[0] == 0x12 && b[1] == 0x20 => Ok(b[2..].try_into().unwrap()),
+        _ => Err(MagnetError::InvalidInfoHash(hash.to_string())),
+    }
+}
+
+pub fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// RFC 4648 alphabet, without padding.
+fn decode_base32(s: &str) -> Option<Vec<u8>> {
+    let mut ret = Vec::with_capacity(s.len() * 5 / 8);
+    let mut bits = 0_u32;
+    let mut len = 0;
+    for c in s.bytes() {
+        let v = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        bits = (bits << 5) | v as u32;
+        len += 5;
+        if len >= 8 {
+            len -= 8;
+            ret.push((bits >> len) as u8);
+        }
+    }
+    Some(ret)
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            ret.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            ret.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(ret).ok()
+}
+
+// host:port with a non empty host and a valid port.
+fn is_host_port(s: &str) -> bool {
+    match s.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+// comma separated indexes and inclusive ranges, i.e. 0,2,4-6
+fn parse_select_only(s: &str) -> Option<Vec<usize>> {
+    let mut ret = vec![];
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((a, b)) => {
+                let (a, b): (usize, usize) = (a.parse().ok()?, b.parse().ok()?);
+                if a > b {
+                    return None;
+                }
+                ret.extend(a..=b);
+            }
+            None => ret.push(part.parse().ok()?),
+        }
+    }
+    Some(ret)
+}
+
+#[cfg(test)]
+mod magnet_test {
+    use super::*;
+
+    const HASH: [u8; 20] = [
+        0xc1, 0x2f, 0xe1, 0xc0, 0x6b, 0xba, 0x25, 0x4a, 0x9d, 0xc9, 0xf5, 0x19, 0xb3, 0x35, 0xaa,
+        0x7c, 0x13, 0x67, 0xa8, 0x8a,
+    ];
+
+    #[test]
+    fn test_hex() {
+        let m = Magnet::parse(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=foo+bar%21%2B\
+             &tr=udp%3A%2F%2Ftracker%3A80&tr.1=http%3A%2F%2Fother%2Fannounce\
+             &ws=http%3A%2F%2Fmirror%2F&x.pe=127.0.0.1:6881&x.pe=[::1]:6882&x.pe=peer.example:6883&x.pe=nope&so=0,2,4-6",
+        )
+        .unwrap();
+        assert_eq!(m.info_hash, HASH);
+        assert_eq!(m.name.as_deref(), Some("foo bar!+"));
+        assert_eq!(m.trackers, ["udp://tracker:80", "http://other/announce"]);
+        assert_eq!(m.web_seeds, ["http://mirror/"]);
+        assert_eq!(
+            m.peers,
+            [
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:6882".parse().unwrap()
+            ]
+        );
+        assert_eq!(m.peer_hosts, ["peer.example:6883"]);
+        assert_eq!(m.select_only, Some(vec![0, 2, 4, 5, 6]));
+    }
+
+    #[test]
+    fn test_base32() {
+        let m = Magnet::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(m.info_hash, HASH);
+        assert_eq!(m.name, None);
+        assert!(m.trackers.is_empty());
+    }
+
+    #[test]
+    fn test_btmh() {
+        let hex = "1220".to_string() + &"ab".repeat(32);
+        let m = Magnet::parse(&format!("magnet:?xt=urn:btmh:{}", hex)).unwrap();
+        assert_eq!(m.info_hash_v2, Some([0xab; 32]));
+        assert_eq!(m.info_hash, [0xab; 20]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            Magnet::parse("http://example.com"),
+            Err(MagnetError::NotMagnet)
+        );
+        assert_eq!(
+            Magnet::parse("magnet:?dn=foo"),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert_eq!(
+            Magnet::parse("magnet:?xt=urn:btih:1234"),
+            Err(MagnetError::InvalidInfoHash("1234".to_string()))
+        );
+        assert_eq!(
+            Magnet::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&so=3-1"),
+            Err(MagnetError::InvalidParam("so".to_string()))
+        );
+    }
+}
@@ -1,9 +1,14 @@
 use std::error::Error;
 
 mod bencode;
+mod create;
+mod dht;
 mod field;
 mod file;
 mod hash;
+mod lsd;
+mod magnet;
+mod metainfo;
 mod tcp_bt;
 mod torrent;
 mod tracker;
@@ -18,16 +23,116 @@ async fn main() -> Result<(), Box<dyn Error>> {
         panic!("no torrent file specified");
     };
 
-    // read bytes from torrent file
-    let bytes: Vec<u8> = tokio::fs::read(arg).await?;
+    // torrent-rs create <path> <out.torrent> [announce] [options]
+    if arg == "create" {
+        let (path, out) = match (args.get(2), args.get(3)) {
+            (Some(p), Some(o)) => (p, o),
+            _ => panic!("usage: create <path> <out.torrent> [announce] [options]"),
+        };
+        let mut builder = create::TorrentBuilder::new(path);
+        let mut options = &args[4..];
+        if let Some(a) = options.first().filter(|a| !a.starts_with("--")) {
+            builder = builder.announce(a);
+            options = &options[1..];
+        }
+        let builder = create_options(builder, options);
+        let bytes = tokio::task::spawn_blocking(move || builder.build()).await??;
+        tokio::fs::write(out, bytes).await?;
+        return Ok(());
+    }
+
+    // torrent-rs scrape <torrent>
+    if arg == "scrape" {
+        let path = match args.get(2) {
+            Some(p) => p,
+            None => panic!("usage: scrape <torrent>"),
+        };
+        let bytes = tokio::fs::read(path).await?;
+        let meta = metainfo::Metainfo::from_bytes(&bytes)?;
+        println!("{}", meta);
+        let trackers = tracker::TrackerList::new(&meta);
+        if trackers.is_empty() {
+            println!("no trackers");
+        }
+        let info_hashes = meta.info_hashes();
+        for i in 0..trackers.tiers().len() {
+            match trackers.scrape_tier(i, &info_hashes).await {
+                Ok(stats) => {
+                    for hash in &info_hashes {
+                        if let Some(s) = stats.get(hash) {
+                            println!(
+                                "tier {} {}: {} seeders, {} leechers, {} completed",
+                                i,
+                                metainfo::hex(hash),
+                                s.seeders,
+                                s.leechers,
+                                s.completed
+                            );
+                        }
+                    }
+                }
+                Err(e) => eprintln!("tier {}: {}", i, e),
+            }
+        }
+        return Ok(());
+    }
+
+    // magnet links start without metadata, files are made once it arrives.
+    let client = if arg.starts_with("magnet:") {
+        torrent::Client::from_magnet(&magnet::Magnet::parse(arg)?)
+    } else {
+        // read bytes from torrent file
+        let bytes: Vec<u8> = tokio::fs::read(arg).await?;
 
-    // create torrent object to parse torrent file
-    let client = torrent::Client::new(&bytes).await;
+        // create torrent object to parse torrent file
+        torrent::Client::new(&bytes).await?
+    };
     // download torrent
     client.start().await;
     Ok(())
 }
 
+// --tier a,b and --web-seed may repeat, tiers are kept in order.
+fn create_options(mut builder: create::TorrentBuilder, args: &[String]) -> create::TorrentBuilder {
+    let mut tiers: Vec<Vec<String>> = vec![];
+    let mut web_seeds = vec![];
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        if flag == "--private" {
+            builder = builder.private(true);
+            continue;
+        }
+        let value = match args.next() {
+            Some(v) => v,
+            None => panic!("{} needs a value", flag),
+        };
+        let number = || match value.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => panic!("{} expects a number, got {}", flag, value),
+        };
+        builder = match flag.as_str() {
+            "--tier" => {
+                tiers.push(value.split(',').map(String::from).collect());
+                builder
+            }
+            "--web-seed" => {
+                web_seeds.push(value.clone());
+                builder
+            }
+            "--comment" => builder.comment(value),
+            "--created-by" => builder.created_by(value),
+            "--piece-length" => builder.piece_length(number()),
+            "--threads" => builder.threads(number()),
+            _ => panic!(
+                "unknown option {}, expected --tier, --web-seed, --comment, \
+                 --created-by, --private, --piece-length or --threads",
+                flag
+            ),
+        };
+    }
+    builder.announce_list(tiers).url_list(web_seeds)
+}
+
 #[cfg(test)]
 mod client_test {
     #[test]
@@ -38,7 +143,7 @@ mod client_test {
         let file_path = "./Elewder.torrent";
         tokio_test::block_on(async {
             let content_bytes = tokio::fs::read(&file_path).await.unwrap();
-            let client = torrent::Client::new(&content_bytes).await;
+            let client = torrent::Client::new(&content_bytes).await.unwrap();
             dbg!(client);
         })
     }
@@ -0,0 +1,1042 @@
+// typed model of a .torrent file, parsed once and read-only afterwards.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    fmt::Display,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    bencode::{
+        de::from_item,
+        decode::{parse_ref, DecodeError},
+        error::Error as FieldError,
+        Item,
+    },
+    hash::{merkle_root, pad_hash, BLOCK_LEN},
+    magnet::Magnet,
+    tracker::{get_info_hash, get_info_hash_v2},
+};
+
+#[derive(Debug)]
+pub enum MetainfoError {
+    // not valid bencode.
+    Decode(DecodeError),
+    // valid bencode, but a field is missing or has the wrong type.
+    Field(FieldError),
+    // well formed, but breaks the invariants listed, every violation is reported.
+    Invalid(Vec<ValidationError>),
+    // an info dict from a peer that doesn't hash to the torrent's info hash.
+    InfoHashMismatch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    ZeroPieceLength,
+    // neither v1 `pieces` nor a v2 file tree, nothing to check pieces against.
+    MissingPieces,
+    // length of the pieces string.
+    PiecesNotMultipleOf20(usize),
+    PieceCountMismatch { expected: usize, actual: usize },
+    // a file path that appears more than once.
+    DuplicatePath(String),
+    // empty, `.` or `..` components, or separators inside a component.
+    UnsafePath(String),
+    // v2 piece length must be a power of two of at least 16 KiB.
+    PieceLengthNotPowerOfTwo(usize),
+    // a v2 file larger than a piece without its `piece layers` entry.
+    MissingPieceLayer(String),
+    // a piece layer of the wrong size, or that doesn't hash up to the file's root.
+    PieceLayerMismatch(String),
+    // a hybrid torrent whose v1 file list disagrees with its v2 file tree, or
+    // whose v1 files aren't padded onto piece boundaries.
+    HybridMismatch(String),
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::ZeroPieceLength => write!(f, "piece length is zero"),
+            ValidationError::MissingPieces => write!(f, "no piece hashes"),
+            ValidationError::PiecesNotMultipleOf20(len) => {
+                write!(f, "pieces length {} is not a multiple of 20", len)
+            }
+            ValidationError::PieceCountMismatch { expected, actual } => write!(
+                f,
+                "expected {} pieces for the total length, found {}",
+                expected, actual
+            ),
+            ValidationError::DuplicatePath(p) => write!(f, "duplicate file path {}", p),
+            ValidationError::UnsafePath(p) => write!(f, "unsafe file path {}", p),
+            ValidationError::PieceLengthNotPowerOfTwo(len) => {
+                write!(f, "piece length {} is not a power of two >= 16 KiB", len)
+            }
+            ValidationError::MissingPieceLayer(p) => write!(f, "missing piece layer for {}", p),
+            ValidationError::PieceLayerMismatch(p) => {
+                write!(f, "piece layer does not match pieces root for {}", p)
+            }
+            ValidationError::HybridMismatch(p) => {
+                write!(f, "v1 and v2 file lists disagree at {}", p)
+            }
+        }
+    }
+}
+
+impl Display for MetainfoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetainfoError::Decode(e) => write!(f, "invalid bencode: {}", e),
+            MetainfoError::Field(e) => write!(f, "invalid metainfo: {}", e),
+            MetainfoError::Invalid(errors) => {
+                write!(f, "invalid metainfo: ")?;
+                for (i, e) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", e)?;
+                }
+                Ok(())
+            }
+            MetainfoError::InfoHashMismatch => write!(f, "info dict does not match info hash"),
+        }
+    }
+}
+
+impl Error for MetainfoError {}
+
+impl From<DecodeError> for MetainfoError {
+    fn from(e: DecodeError) -> Self {
+        MetainfoError::Decode(e)
+    }
+}
+
+impl From<FieldError> for MetainfoError {
+    fn from(e: FieldError) -> Self {
+        MetainfoError::Field(e)
+    }
+}
+
+// https://wiki.theory.org/BitTorrentSpecification#Metainfo_File_Structure
+#[derive(Deserialize)]
+struct RawMetainfo {
+    announce: Option<String>,
+    #[serde(rename = "announce-list", default)]
+    announce_list: Vec<Vec<String>>,
+    comment: Option<String>,
+    #[serde(rename = "created by")]
+    created_by: Option<String>,
+    #[serde(rename = "creation date")]
+    creation_date: Option<i64>,
+    encoding: Option<String>,
+    #[serde(rename = "url-list")]
+    url_list: Option<UrlList>,
+    info: RawInfo,
+}
+
+// BEP 19 allows a single url or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct RawInfo {
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: u64,
+    // v1 only, v2 hashes live in the file tree and `piece layers`.
+    #[serde(default, with = "serde_bytes")]
+    pieces: Option<Vec<u8>>,
+    private: Option<i64>,
+    #[serde(rename = "meta version")]
+    meta_version: Option<i64>,
+    // single file mode
+    length: Option<u64>,
+    // multiple file mode
+    files: Option<Vec<RawFile>>,
+}
+
+#[derive(Deserialize)]
+struct RawFile {
+    length: u64,
+    path: Vec<String>,
+    // BEP 47 attributes, `p` marks a padding file.
+    attr: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    // path relative to the download directory, name of the torrent first.
+    pub path: Vec<String>,
+    pub length: usize,
+    // v2 merkle root of the file's 16 KiB blocks, none for empty files.
+    pub pieces_root: Option<[u8; 32]>,
+    // padding that aligns the next file to a piece, never written to disk.
+    pub pad: bool,
+}
+
+impl FileInfo {
+    fn new(path: Vec<String>, length: usize) -> Self {
+        FileInfo {
+            path,
+            length,
+            pieces_root: None,
+            pad: false,
+        }
+    }
+}
+
+// what a v2 piece must hash up to, see hash::verify_piece_v2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceV2 {
+    // piece layer entry, or the pieces root for files no bigger than a piece.
+    pub hash: [u8; 32],
+    // bytes of file data in the piece, the rest is padding.
+    pub len: usize,
+    // leaves in the piece's subtree.
+    pub width: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Metainfo {
+    // v1 info hash, or the truncated v2 hash for v2 only torrents.
+    info_hash: [u8; 20],
+    info_hash_v2: Option<[u8; 32]>,
+    announce: Option<String>,
+    announce_list: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    encoding: Option<String>,
+    private: bool,
+    url_list: Vec<String>,
+    name: String,
+    piece_length: usize,
+    pieces: Option<Vec<u8>>,
+    // pieces root -> concatenated piece hashes, for v2 files bigger than a piece.
+    piece_layers: BTreeMap<Vec<u8>, Vec<u8>>,
+    // v2 files in file tree order without padding, empty for v1 only torrents.
+    file_tree: Vec<FileInfo>,
+    files: Vec<FileInfo>,
+    total_length: usize,
+    // the bencoded info dict exactly as hashed, served to ut_metadata peers.
+    info: Vec<u8>,
+    // started from a magnet link and still waiting on the info dict.
+    pending: bool,
+}
+
+fn to_usize(value: u64, field: &str) -> Result<usize, MetainfoError> {
+    usize::try_from(value)
+        .map_err(|_| FieldError::new(format!("{} out of range", value)).in_field(field.as_bytes()))
+        .map_err(MetainfoError::from)
+}
+
+impl Metainfo {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetainfoError> {
+        let root = parse_ref(bytes)?;
+        // hash exactly the bytes the info dict was decoded from.
+        let raw_info = match root.get_raw(b"info") {
+            Some(info) => info,
+            None => return Err(FieldError::new("missing field `info`").into()),
+        };
+        let root = Item::from(root);
+        let raw: RawMetainfo = from_item(root.clone())?;
+        let info = raw.info;
+        let piece_length = to_usize(info.piece_length, "piece length")?;
+
+        // https://www.bittorrent.org/beps/bep_0052.html
+        let v2_files = match info.meta_version {
+            Some(2) => match root.get_path(&["info", "file tree"]) {
+                Some(tree) => {
+                    let mut files = vec![];
+                    parse_file_tree(tree, &mut vec![info.name.clone()], &mut files)
+                        .map_err(|e| e.in_field(b"file tree").in_field(b"info"))?;
+                    // a lone top level file is a single file torrent, not kept in a folder.
+                    if files.len() == 1 && files[0].path.len() == 2 {
+                        files[0].path.remove(0);
+                    }
+                    Some(files)
+                }
+                None => {
+                    return Err(FieldError::new("missing field `file tree`")
+                        .in_field(b"info")
+                        .into())
+                }
+            },
+            _ => None,
+        };
+        let info_hash_v2 = v2_files.as_ref().map(|_| get_info_hash_v2(raw_info));
+        let info_hash = match (&info.pieces, info_hash_v2) {
+            (None, Some(v2)) => {
+                let mut truncated = [0u8; 20];
+                truncated.copy_from_slice(&v2[..20]);
+                truncated
+            }
+            _ => get_info_hash(raw_info),
+        };
+        let mut piece_layers = BTreeMap::new();
+        if v2_files.is_some() {
+            if let Some(layers) = root.get("piece layers").and_then(Item::as_dict) {
+                for (k, v) in layers {
+                    match v.as_bytes() {
+                        Some(b) => piece_layers.insert(k.clone(), b.to_vec()),
+                        None => {
+                            return Err(FieldError::new("expected a byte string")
+                                .in_field(k)
+                                .in_field(b"piece layers")
+                                .into())
+                        }
+                    };
+                }
+            }
+        }
+
+        let file_tree = v2_files.clone().unwrap_or_default();
+        let mut files = match (info.length, info.files, v2_files) {
+            (Some(length), None, _) => vec![FileInfo::new(
+                vec![info.name.clone()],
+                to_usize(length, "length")?,
+            )],
+            (None, Some(files), _) => {
+                let mut ret = Vec::with_capacity(files.len());
+                for f in files {
+                    let mut path = vec![info.name.clone()];
+                    path.extend(f.path);
+                    let mut file = FileInfo::new(path, to_usize(f.length, "length")?);
+                    file.pad = f.attr.is_some_and(|a| a.contains('p'));
+                    ret.push(file);
+                }
+                ret
+            }
+            (None, None, Some(files)) => align_files(files, piece_length),
+            _ => {
+                return Err(
+                    FieldError::new("expected exactly one of `length` or `files`")
+                        .in_field(b"info")
+                        .into(),
+                )
+            }
+        };
+        // hybrid torrents lay files out by the v1 list, roots come from the file tree.
+        if info.pieces.is_some() {
+            let v1 = files.iter_mut().filter(|f| !f.pad);
+            for (f, v2) in v1.zip(&file_tree) {
+                if f.path == v2.path && f.length == v2.length {
+                    f.pieces_root = v2.pieces_root;
+                }
+            }
+        }
+        let total_length = files.iter().map(|f| f.length).sum();
+
+        Ok(Metainfo {
+            info_hash,
+            info_hash_v2,
+            announce: raw.announce,
+            announce_list: raw.announce_list,
+            comment: raw.comment,
+            created_by: raw.created_by,
+            creation_date: raw.creation_date,
+            encoding: raw.encoding,
+            private: info.private == Some(1),
+            url_list: match raw.url_list {
+                Some(UrlList::One(u)) => vec![u],
+                Some(UrlList::Many(l)) => l,
+                None => vec![],
+            },
+            name: info.name,
+            piece_length,
+            pieces: info.pieces,
+            piece_layers,
+            file_tree,
+            files,
+            total_length,
+            info: raw_info.to_vec(),
+            pending: false,
+        })
+    }
+
+    // what a magnet link tells us before any peer sends the info dict, each
+    // tracker gets its own tier.
+    pub fn from_magnet(magnet: &Magnet) -> Self {
+        Metainfo {
+            info_hash: magnet.info_hash,
+            info_hash_v2: magnet.info_hash_v2,
+            announce: magnet.trackers.first().cloned(),
+            announce_list: magnet.trackers.iter().map(|t| vec![t.clone()]).collect(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            encoding: None,
+            private: false,
+            url_list: magnet.web_seeds.clone(),
+            name: magnet.name.clone().unwrap_or_default(),
+            piece_length: 0,
+            pieces: None,
+            piece_layers: BTreeMap::new(),
+            file_tree: vec![],
+            files: vec![],
+            total_length: 0,
+            info: vec![],
+            pending: true,
+        }
+    }
+
+    // completes a pending metainfo with an info dict fetched from peers, which
+    // must hash to one of the known info hashes.
+    pub fn with_info(&self, info: &[u8]) -> Result<Self, MetainfoError> {
+        let mut bytes = b"d4:info".to_vec();
+        bytes.extend_from_slice(info);
+        bytes.push(b'e');
+        let mut meta = Metainfo::from_bytes(&bytes)?;
+        let matches = match (meta.info_hash_v2, self.info_hash_v2) {
+            (Some(a), Some(b)) => a == b,
+            _ => meta.info_hashes().contains(&self.info_hash),
+        };
+        if !matches {
+            return Err(MetainfoError::InfoHashMismatch);
+        }
+        meta.announce = self.announce.clone();
+        meta.announce_list = self.announce_list.clone();
+        meta.url_list = self.url_list.clone();
+        Ok(meta)
+    }
+
+    // raw info dict, empty while pending.
+    pub fn info_bytes(&self) -> &[u8] {
+        &self.info
+    }
+
+    // true until the info dict arrives, when there are no files or pieces yet.
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    // checks the invariants piece and file io rely on, before any file is created.
+    pub fn validate(&self) -> Result<(), MetainfoError> {
+        let mut errors = vec![];
+        if self.piece_length == 0 {
+            errors.push(ValidationError::ZeroPieceLength);
+        }
+        if !self.is_v1() && !self.is_v2() {
+            errors.push(ValidationError::MissingPieces);
+        }
+        if !self.pieces().len().is_multiple_of(20) {
+            errors.push(ValidationError::PiecesNotMultipleOf20(self.pieces().len()));
+        }
+        if self.is_v2() && (self.piece_length < BLOCK_LEN || !self.piece_length.is_power_of_two()) {
+            errors.push(ValidationError::PieceLengthNotPowerOfTwo(self.piece_length));
+        }
+        if self.piece_length > 0 {
+            let expected = self.total_length.div_ceil(self.piece_length);
+            if expected != self.num_pieces() {
+                errors.push(ValidationError::PieceCountMismatch {
+                    expected,
+                    actual: self.num_pieces(),
+                });
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for f in self.files.iter().filter(|f| !f.pad) {
+            let path = f.path.join("/");
+            let unsafe_path = f.path.iter().any(|c| {
+                c.is_empty() || c == "." || c == ".." || c.contains('/') || c.contains('\\')
+            });
+            if unsafe_path {
+                errors.push(ValidationError::UnsafePath(path));
+            } else if !seen.insert(path.clone()) {
+                errors.push(ValidationError::DuplicatePath(path));
+            }
+        }
+        if self.is_v1() && self.is_v2() {
+            self.validate_hybrid(&mut errors);
+        }
+        if self.is_v2() && errors.is_empty() {
+            self.validate_piece_layers(&mut errors);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(MetainfoError::Invalid(errors))
+        }
+    }
+
+    // a hybrid's v1 files must be the file tree's files, each starting on a piece.
+    fn validate_hybrid(&self, errors: &mut Vec<ValidationError>) {
+        let v1 = self.files.iter().filter(|f| !f.pad).collect::<Vec<_>>();
+        for i in 0..v1.len().max(self.file_tree.len()) {
+            match (v1.get(i).copied(), self.file_tree.get(i)) {
+                (Some(a), Some(b)) if a.path == b.path && a.length == b.length => {}
+                (Some(f), _) | (None, Some(f)) => {
+                    errors.push(ValidationError::HybridMismatch(f.path.join("/")));
+                    return;
+                }
+                (None, None) => {}
+            }
+        }
+        let mut offset = 0_usize;
+        for f in &self.files {
+            if !f.pad && f.length > 0 && !offset.is_multiple_of(self.piece_length.max(1)) {
+                errors.push(ValidationError::HybridMismatch(f.path.join("/")));
+                return;
+            }
+            offset += f.length;
+        }
+    }
+
+    // checks every v2 piece layer hashes up to its file's pieces root.
+    fn validate_piece_layers(&self, errors: &mut Vec<ValidationError>) {
+        let width = self.piece_length / BLOCK_LEN;
+        for f in &self.files {
+            let root = match f.pieces_root {
+                Some(r) if f.length > self.piece_length => r,
+                _ => continue,
+            };
+            let path = f.path.join("/");
+            let layer = match self.piece_layers.get(&root[..]) {
+                Some(l) => l,
+                None => {
+                    errors.push(ValidationError::MissingPieceLayer(path));
+                    continue;
+                }
+            };
+            let num_pieces = f.length.div_ceil(self.piece_length);
+            if layer.len() != num_pieces * 32 {
+                errors.push(ValidationError::PieceLayerMismatch(path));
+                continue;
+            }
+            let hashes = layer
+                .chunks(32)
+                .map(|c| c.try_into().unwrap())
+                .collect::<Vec<_>>();
+            let leaves = f.length.div_ceil(BLOCK_LEN).next_power_of_two();
+            if merkle_root(hashes, leaves / width, pad_hash(width)) != root {
+                errors.push(ValidationError::PieceLayerMismatch(path));
+            }
+        }
+    }
+
+    // v1 info hash, or the v2 hash truncated to 20 bytes when there are no v1 pieces.
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    // SHA-256 of the info dict, for torrents with meta version 2.
+    pub fn info_hash_v2(&self) -> Option<[u8; 32]> {
+        self.info_hash_v2
+    }
+
+    // has v1 `pieces`, hybrid torrents are both v1 and v2.
+    pub fn is_v1(&self) -> bool {
+        self.pieces.is_some()
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.info_hash_v2.is_some()
+    }
+
+    // every 20 byte hash the swarm may know this torrent by, hybrids have two.
+    pub fn info_hashes(&self) -> Vec<[u8; 20]> {
+        let mut hashes = vec![self.info_hash];
+        if let (true, Some(v2)) = (self.is_v1(), self.info_hash_v2) {
+            let mut truncated = [0u8; 20];
+            truncated.copy_from_slice(&v2[..20]);
+            hashes.push(truncated);
+        }
+        hashes
+    }
+
+    pub fn announce(&self) -> Option<&str> {
+        self.announce.as_deref()
+    }
+
+    // BEP 12 tiers, each a list of tracker urls.
+    pub fn announce_list(&self) -> &[Vec<String>] {
+        &self.announce_list
+    }
+
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    pub fn created_by(&self) -> Option<&str> {
+        self.created_by.as_deref()
+    }
+
+    // seconds since the unix epoch.
+    pub fn creation_date(&self) -> Option<i64> {
+        self.creation_date
+    }
+
+    pub fn encoding(&self) -> Option<&str> {
+        self.encoding.as_deref()
+    }
+
+    pub fn private(&self) -> bool {
+        self.private
+    }
+
+    // BEP 19 web seeds.
+    pub fn url_list(&self) -> &[String] {
+        &self.url_list
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn piece_length(&self) -> usize {
+        self.piece_length
+    }
+
+    // concatenated 20 byte SHA-1 hashes, one per piece, empty for v2 only torrents.
+    pub fn pieces(&self) -> &[u8] {
+        self.pieces.as_deref().unwrap_or_default()
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        match self.pieces {
+            Some(ref p) => p.len() / 20,
+            None if self.piece_length > 0 => self.total_length.div_ceil(self.piece_length),
+            None => 0,
+        }
+    }
+
+    // one entry per piece for v2 and hybrid torrents, in piece order, empty otherwise.
+    pub fn pieces_v2(&self) -> Vec<PieceV2> {
+        let mut ret = vec![];
+        if !self.is_v2() || self.piece_length == 0 {
+            return ret;
+        }
+        let width = self.piece_length / BLOCK_LEN;
+        for f in &self.files {
+            let root = match f.pieces_root {
+                Some(r) if !f.pad => r,
+                _ => continue,
+            };
+            if f.length <= self.piece_length {
+                ret.push(PieceV2 {
+                    hash: root,
+                    len: f.length,
+                    width: f.length.div_ceil(BLOCK_LEN).next_power_of_two(),
+                });
+                continue;
+            }
+            let layer = self.piece_layers.get(&root[..]);
+            for i in 0..f.length.div_ceil(self.piece_length) {
+                let mut hash = [0u8; 32];
+                if let Some(h) = layer.and_then(|l| l.get(i * 32..(i + 1) * 32)) {
+                    hash.copy_from_slice(h);
+                }
+                ret.push(PieceV2 {
+                    hash,
+                    len: (f.length - i * self.piece_length).min(self.piece_length),
+                    width,
+                });
+            }
+        }
+        ret
+    }
+
+    // single file torrents hold one file named after the torrent.
+    pub fn files(&self) -> &[FileInfo] {
+        &self.files
+    }
+
+    pub fn total_length(&self) -> usize {
+        self.total_length
+    }
+}
+
+// lowercase hex, how info hashes are usually shown.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// a summary of the torrent, one field per line.
+impl Display for Metainfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "name: {}", self.name())?;
+        write!(f, "\ninfo hash: {}", hex(&self.info_hash()))?;
+        if let Some(v2) = self.info_hash_v2() {
+            write!(f, "\ninfo hash v2: {}", hex(&v2))?;
+        }
+        write!(
+            f,
+            "\nsize: {} bytes in {} pieces",
+            self.total_length(),
+            self.num_pieces()
+        )?;
+        if let Some(comment) = self.comment() {
+            write!(f, "\ncomment: {}", comment)?;
+        }
+        if let Some(created_by) = self.created_by() {
+            write!(f, "\ncreated by: {}", created_by)?;
+        }
+        if let Some(date) = self.creation_date() {
+            write!(f, "\ncreation date: {}", date)?;
+        }
+        if let Some(encoding) = self.encoding() {
+            write!(f, "\nencoding: {}", encoding)?;
+        }
+        if !self.url_list().is_empty() {
+            write!(f, "\nweb seeds: {}", self.url_list().join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// walks a BEP 52 file tree, a file is a dict holding an empty key.
+fn parse_file_tree(
+    node: &Item,
+    path: &mut Vec<String>,
+    files: &mut Vec<FileInfo>,
+) -> Result<(), FieldError> {
+    let dict = match node.as_dict() {
+        Some(d) => d,
+        None => return Err(FieldError::new("expected a dict")),
+    };
+    for (key, value) in dict {
+        if key.is_empty() {
+            let length = match value.get("length").and_then(Item::as_usize) {
+                Some(l) => l,
+                None => return Err(FieldError::new("missing field `length`").in_field(b"")),
+            };
+            let pieces_root = match value.get("pieces root").and_then(Item::as_bytes) {
+                Some(r) if r.len() == 32 => Some(r.try_into().unwrap()),
+                _ if length == 0 => None,
+                _ => return Err(FieldError::new("expected 32 byte `pieces root`").in_field(b"")),
+            };
+            files.push(FileInfo {
+                path: path.clone(),
+                length,
+                pieces_root,
+                pad: false,
+            });
+            continue;
+        }
+        let name = match std::str::from_utf8(key) {
+            Ok(n) => n.to_string(),
+            Err(_) => return Err(FieldError::new("path is not utf-8").in_field(key)),
+        };
+        path.push(name);
+        let res = parse_file_tree(value, path, files);
+        path.pop();
+        res.map_err(|e| e.in_field(key))?;
+    }
+    Ok(())
+}
+
+// v2 files each start on a piece boundary, so padding is put between them
+// to keep the usual contiguous layout.
+fn align_files(files: Vec<FileInfo>, piece_length: usize) -> Vec<FileInfo> {
+    let mut ret = Vec::with_capacity(files.len());
+    let mut offset = 0_usize;
+    for f in files {
+        if f.length > 0 && piece_length > 0 && !offset.is_multiple_of(piece_length) {
+            let pad = piece_length - offset % piece_length;
+            ret.push(FileInfo {
+                path: vec![".pad".to_string(), pad.to_string()],
+                length: pad,
+                pieces_root: None,
+                pad: true,
+            });
+            offset += pad;
+        }
+        offset += f.length;
+        ret.push(f);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod metainfo_test {
+    use super::*;
+    use crate::{
+        bencode::{decode::parse, encode::encode},
+        hash::{block_hashes, verify_piece_v2},
+    };
+
+    fn dict(entries: Vec<(&str, Item)>) -> Item {
+        Item::Dict(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v))
+                .collect(),
+        )
+    }
+
+    // a v2 only torrent with a three piece file and a one piece file, 16 KiB pieces.
+    fn v2_torrent(big: &[u8], small: &[u8], layer: Vec<u8>) -> Vec<u8> {
+        let big_root = merkle_root(block_hashes(big), 4, [0; 32]);
+        let small_root = merkle_root(block_hashes(small), 1, [0; 32]);
+        let file = |len: usize, root: [u8; 32]| {
+            dict(vec![(
+                "",
+                dict(vec![
+                    ("length", Item::Integer(len as i64)),
+                    ("pieces root", Item::String(root.to_vec())),
+                ]),
+            )])
+        };
+        let info = dict(vec![
+            (
+                "file tree",
+                dict(vec![
+                    ("a", file(big.len(), big_root)),
+                    ("b", dict(vec![("c", file(small.len(), small_root))])),
+                ]),
+            ),
+            ("meta version", Item::Integer(2)),
+            ("name", Item::String(b"v2".to_vec())),
+            ("piece length", Item::Integer(BLOCK_LEN as i64)),
+        ]);
+        encode(&dict(vec![
+            ("info", info),
+            (
+                "piece layers",
+                Item::Dict([(big_root.to_vec(), Item::String(layer))].into()),
+            ),
+        ]))
+    }
+
+    #[test]
+    fn test_single_file() {
+        let bytes = b"d8:announce9:udp://a:113:announce-listll9:udp://a:1el9:udp://b:2ee7:comment2:hi10:created by4:test13:creation datei1600000000e8:url-list8:http://w4:infod6:lengthi20e4:name3:foo12:piece lengthi16e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbb7:privatei1eee";
+        let meta = Metainfo::from_bytes(bytes).unwrap();
+        assert_eq!(meta.announce(), Some("udp://a:1"));
+        assert_eq!(meta.announce_list().len(), 2);
+        assert_eq!(meta.comment(), Some("hi"));
+        assert_eq!(meta.created_by(), Some("test"));
+        assert_eq!(meta.creation_date(), Some(1600000000));
+        assert_eq!(meta.url_list(), ["http://w".to_string()]);
+        assert!(meta.private());
+        assert_eq!(meta.piece_length(), 16);
+        assert_eq!(meta.num_pieces(), 2);
+        assert_eq!(meta.files(), [FileInfo::new(vec!["foo".to_string()], 20)]);
+        assert_eq!(meta.total_length(), 20);
+
+        let summary = meta.to_string();
+        assert!(summary.starts_with("name: foo\ninfo hash: "));
+        assert!(summary.contains("\ncomment: hi\ncreated by: test\n"));
+        assert!(summary.ends_with("\nweb seeds: http://w"));
+    }
+
+    #[test]
+    fn test_multi_file() {
+        let bytes = b"d4:infod5:filesld6:lengthi3e4:pathl1:a1:beed6:lengthi4e4:pathl1:ceee4:name3:dir12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let meta = Metainfo::from_bytes(bytes).unwrap();
+        assert_eq!(meta.announce(), None);
+        assert!(!meta.private());
+        assert_eq!(meta.files()[0].path, ["dir", "a", "b"]);
+        assert_eq!(meta.files()[1].path, ["dir", "c"]);
+        assert_eq!(meta.total_length(), 7);
+    }
+
+    #[test]
+    fn test_validate() {
+        let ok = b"d4:infod6:lengthi20e4:name3:foo12:piece lengthi16e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee";
+        assert!(Metainfo::from_bytes(ok).unwrap().validate().is_ok());
+
+        let zero =
+            b"d4:infod6:lengthi20e4:name3:foo12:piece lengthi0e6:pieces21:aaaaaaaaaaaaaaaaaaaaaee";
+        let errors = match Metainfo::from_bytes(zero).unwrap().validate() {
+            Err(MetainfoError::Invalid(e)) => e,
+            _ => panic!("expected validation errors"),
+        };
+        assert_eq!(
+            errors,
+            [
+                ValidationError::ZeroPieceLength,
+                ValidationError::PiecesNotMultipleOf20(21)
+            ]
+        );
+
+        let hashless = b"d4:infod6:lengthi20e4:name3:foo12:piece lengthi16eee";
+        assert!(matches!(
+            Metainfo::from_bytes(hashless).unwrap().validate(),
+            Err(MetainfoError::Invalid(e)) if e == [ValidationError::MissingPieces]
+        ));
+
+        let bad = b"d4:infod5:filesld6:lengthi3e4:pathl1:aeed6:lengthi4e4:pathl1:aeed6:lengthi1e4:pathl2:..1:beee4:name3:dir12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let errors = match Metainfo::from_bytes(bad).unwrap().validate() {
+            Err(MetainfoError::Invalid(e)) => e,
+            _ => panic!("expected validation errors"),
+        };
+        assert_eq!(
+            errors,
+            [
+                ValidationError::PieceCountMismatch {
+                    expected: 2,
+                    actual: 1
+                },
+                ValidationError::DuplicatePath("dir/a".to_string()),
+                ValidationError::UnsafePath("dir/../b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let missing = b"d4:infod6:lengthi3e4:name3:foo6:pieces0:ee";
+        assert_eq!(
+            Metainfo::from_bytes(missing).unwrap_err().to_string(),
+            "invalid metainfo: field `info`: missing field `piece length`"
+        );
+        let both = b"d4:infod5:filesle6:lengthi3e4:name3:foo12:piece lengthi1e6:pieces0:ee";
+        assert!(matches!(
+            Metainfo::from_bytes(both),
+            Err(MetainfoError::Field(_))
+        ));
+        assert!(matches!(
+            Metainfo::from_bytes(b"d4:info"),
+            Err(MetainfoError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_v2() {
+        let big: Vec<u8> = (0..40000).map(|i| i as u8).collect();
+        let small = b"small file".to_vec();
+        let layer = block_hashes(&big).concat();
+        let bytes = v2_torrent(&big, &small, layer);
+        let meta = Metainfo::from_bytes(&bytes).unwrap();
+        meta.validate().unwrap();
+        assert!(meta.is_v2() && !meta.is_v1());
+
+        let info = parse_ref(&bytes).unwrap().get_raw(b"info").unwrap();
+        let v2 = get_info_hash_v2(info);
+        assert_eq!(meta.info_hash_v2(), Some(v2));
+        assert_eq!(meta.info_hash(), v2[..20]);
+
+        // the small file is padded onto the next piece boundary.
+        let files = meta.files();
+        assert_eq!(files[0].path, ["v2", "a"]);
+        assert!(files[1].pad);
+        assert_eq!(files[1].length, 3 * BLOCK_LEN - 40000);
+        assert_eq!(files[2].path, ["v2", "b", "c"]);
+        assert_eq!(meta.num_pieces(), 4);
+
+        let pieces = meta.pieces_v2();
+        assert_eq!(pieces.len(), 4);
+        for (i, chunk) in big.chunks(BLOCK_LEN).enumerate() {
+            assert!(verify_piece_v2(chunk, &pieces[i]));
+        }
+        assert!(verify_piece_v2(&small, &pieces[3]));
+        assert!(!verify_piece_v2(b"small filf", &pieces[3]));
+    }
+
+    #[test]
+    fn test_v2_bad_layer() {
+        let big = vec![1u8; 40000];
+        let mut layer = block_hashes(&big).concat();
+        layer[0] ^= 1;
+        let meta = Metainfo::from_bytes(&v2_torrent(&big, b"x", layer)).unwrap();
+        let errors = match meta.validate() {
+            Err(MetainfoError::Invalid(e)) => e,
+            _ => panic!("expected validation errors"),
+        };
+        assert_eq!(
+            errors,
+            [ValidationError::PieceLayerMismatch("v2/a".to_string())]
+        );
+    }
+
+    // adds a v1 file list and pieces to the v2 torrent, optionally padding `a`.
+    fn hybrid_torrent(big: &[u8], small: &[u8], padded: bool) -> Vec<u8> {
+        let mut root = parse(&v2_torrent(big, small, block_hashes(big).concat())).unwrap();
+        let file = |len: usize, path: &[&str], attr: &str| {
+            let path = path.iter().map(|p| Item::String(p.as_bytes().to_vec()));
+            dict(vec![
+                ("attr", Item::String(attr.as_bytes().to_vec())),
+                ("length", Item::Integer(len as i64)),
+                ("path", Item::List(path.collect())),
+            ])
+        };
+        let mut files = vec![file(big.len(), &["a"], "")];
+        let mut data = big.to_vec();
+        if padded {
+            let pad = 3 * BLOCK_LEN - big.len();
+            files.push(file(pad, &[".pad", &pad.to_string()], "p"));
+            data.resize(3 * BLOCK_LEN, 0);
+        }
+        files.push(file(small.len(), &["b", "c"], ""));
+        data.extend_from_slice(small);
+        let pieces = data
+            .chunks(BLOCK_LEN)
+            .flat_map(|c| get_info_hash(c).to_vec())
+            .collect();
+        if let Item::Dict(d) = &mut root {
+            if let Some(Item::Dict(info)) = d.get_mut(&b"info"[..]) {
+                info.insert(b"files".to_vec(), Item::List(files));
+                info.insert(b"pieces".to_vec(), Item::String(pieces));
+            }
+        }
+        encode(&root)
+    }
+
+    #[test]
+    fn test_hybrid() {
+        let big: Vec<u8> = (0..40000).map(|i| (i * 3) as u8).collect();
+        let small = b"small file".to_vec();
+        let bytes = hybrid_torrent(&big, &small, true);
+        let meta = Metainfo::from_bytes(&bytes).unwrap();
+        meta.validate().unwrap();
+        assert!(meta.is_v1() && meta.is_v2());
+
+        let info = parse_ref(&bytes).unwrap().get_raw(b"info").unwrap();
+        let hashes = meta.info_hashes();
+        assert_eq!(hashes[0], get_info_hash(info));
+        assert_eq!(hashes[1], get_info_hash_v2(info)[..20]);
+
+        // the padding file comes from the v1 list and keeps its place in the layout.
+        let files = meta.files();
+        assert!(files[1].pad && !files[0].pad && !files[2].pad);
+        assert_eq!(files[0].pieces_root, meta.file_tree[0].pieces_root);
+        assert_eq!(files[2].pieces_root, meta.file_tree[1].pieces_root);
+        assert_eq!(meta.num_pieces(), meta.pieces_v2().len());
+        assert!(verify_piece_v2(&small, &meta.pieces_v2()[3]));
+
+        let unpadded = Metainfo::from_bytes(&hybrid_torrent(&big, &small, false)).unwrap();
+        let errors = match unpadded.validate() {
+            Err(MetainfoError::Invalid(e)) => e,
+            _ => panic!("expected validation errors"),
+        };
+        assert_eq!(
+            errors,
+            [ValidationError::HybridMismatch("v2/b/c".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_magnet() {
+        let bytes = b"d4:infod6:lengthi20e4:name3:foo12:piece lengthi16e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee";
+        let info = &bytes[7..bytes.len() - 1];
+        let magnet = Magnet {
+            info_hash: get_info_hash(info),
+            trackers: vec!["udp://a:1".to_string(), "udp://b:2".to_string()],
+            ..Magnet::default()
+        };
+        let pending = Metainfo::from_magnet(&magnet);
+        assert!(pending.is_pending());
+        assert!(pending.files().is_empty());
+        assert_eq!(pending.announce(), Some("udp://a:1"));
+
+        let meta = pending.with_info(info).unwrap();
+        assert!(!meta.is_pending());
+        assert_eq!(meta.info_hash(), magnet.info_hash);
+        assert_eq!(meta.announce_list().len(), 2);
+        assert_eq!(meta.total_length(), 20);
+
+        let other = b"d6:lengthi20e4:name3:bar12:piece lengthi16e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbe";
+        assert!(matches!(
+            pending.with_info(other),
+            Err(MetainfoError::InfoHashMismatch)
+        ));
+    }
+}
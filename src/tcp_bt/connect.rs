@@ -1,12 +1,16 @@
 #![allow(dead_code)]
 
 use std::sync::{
-    atomic::{AtomicBool, AtomicU32},
+    atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering},
     Arc, Condvar, Mutex,
 };
 
 use tokio::{
-    net::TcpStream,
+    io::AsyncWriteExt,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
     sync::Mutex as TokioMutex,
     task::{self, JoinHandle},
 };
@@ -20,8 +24,15 @@ use crate::{
 };
 
 use super::{
+    extension::{ext_handshake, PeerExt},
     fetch::torrent_fetcher,
+    msg::{
+        structs::{Extended, Handshake},
+        RESERVED_EXTENSION, RESERVED_V2,
+    },
     parse::Parser,
+    peek_handshake,
+    pex::{spawn_pex, Swarm, PEX_REACHABLE},
     seed::{torrent_seeder, Peer},
     send_handshake,
 };
@@ -29,6 +40,12 @@ use super::{
 pub struct Connector {
     pub piece: Condvar,
     pub brk: AtomicBool,
+    // our listen port, advertised in extended handshakes.
+    pub port: AtomicU16,
+    // connected peers for pex, and peers pex and the dht found for the main loop to dial.
+    pub swarm: Mutex<Swarm>,
+    // piece payload sent to peers this session.
+    pub uploaded: AtomicU64,
 }
 
 impl Connector {
@@ -36,12 +53,24 @@ impl Connector {
         Self {
             piece: Condvar::new(),
             brk: AtomicBool::new(false),
+            port: AtomicU16::new(0),
+            swarm: Mutex::new(Swarm::default()),
+            uploaded: AtomicU64::new(0),
         }
     }
 }
 
+impl Default for Connector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// info_hash is the hash to handshake with, incoming peers get whichever of ours they sent.
+#[allow(clippy::too_many_arguments)]
 pub async fn spawn_connecter_task(
     peer: Peer,
+    info_hash: [u8; 20],
     parser: &Arc<Parser>,
     torrent: &Arc<Client>,
     field: &Arc<Mutex<ByteField>>,
@@ -53,59 +82,130 @@ pub async fn spawn_connecter_task(
     let torrent = Arc::clone(torrent);
     let field = Arc::clone(field);
     let count = Arc::clone(count);
-    return task::spawn(async move {
-        let mut stream = match peer {
-            Peer::Stream(s) => s,
+    task::spawn(async move {
+        let mut info_hash = info_hash;
+        // incoming peers have sent their handshake already, outgoing ones answer ours.
+        let mut outgoing = None;
+        let (mut stream, mut theirs) = match peer {
+            // dropped before our handshake unless they want a torrent we serve.
+            Peer::Stream(s) => match peek_handshake(&s).await {
+                Some(h) if torrent.metainfo.info_hashes().contains(&h.info_hash) => {
+                    info_hash = h.info_hash;
+                    (s, Some(h))
+                }
+                _ => return,
+            },
             Peer::Addr(addr) => match TcpStream::connect(&addr).await {
-                Ok(s) => s,
+                Ok(s) => {
+                    outgoing = Some(addr);
+                    (s, None)
+                }
                 Err(_) => return,
             },
         };
-        match send_handshake(&mut stream, torrent.info_hash, torrent.info_hash).await {
+        // tell peers we understand v2, for v2 and hybrid torrents.
+        let mut reserved = Handshake::default().reserved;
+        if torrent.metainfo.is_v2() {
+            reserved[7] |= RESERVED_V2;
+        }
+        match send_handshake(&mut stream, info_hash, torrent.peer_id, reserved).await {
             Some(_) => {}
             None => return,
         }
+        if theirs.is_none() {
+            theirs = peek_handshake(&stream).await;
+        }
+        let peer_addr = stream.peer_addr().ok();
+        let ext = Arc::new(Mutex::new(PeerExt::new(peer_addr)));
+        // extended messages only go to peers that set the reserved bit too.
+        let extended = theirs.is_some_and(|h| h.reserved[5] & RESERVED_EXTENSION != 0);
+        if extended {
+            let port = connector.port.load(Ordering::Relaxed);
+            let ours = ext_handshake(
+                &torrent,
+                &ext.lock().unwrap().registry,
+                port,
+                peer_addr.map(|a| a.ip()),
+            );
+            let msg = Extended::new(0, ours.as_bytes());
+            if stream.write_all(&msg.as_bytes()).await.is_err() {
+                return;
+            }
+        }
         let (reader, writer) = stream.into_split();
         let am_reader = Arc::new(TokioMutex::new(reader));
         let am_writer = Arc::new(TokioMutex::new(writer));
 
-        let mut complete = false;
-        task::block_in_place(|| {
-            let f = field.lock().unwrap();
-            if f.if_full() {
-                complete = true;
-            }
-        });
-        if complete {
-            torrent_seeder(
-                &am_reader, &am_writer, &parser, &torrent, &field, &connector, &count,
-            )
-            .await;
-            return;
+        // only peers we dialed are passed on, the ports of incoming ones are ephemeral.
+        if let Some(addr) = outgoing {
+            connector.swarm.lock().unwrap().connect(addr, PEX_REACHABLE);
         }
-        let v = torrent_fetcher(
-            &am_reader, &am_writer, &parser, &torrent, &field, &connector, &count,
+        let pex = if extended && !torrent.metainfo.private() {
+            Some(spawn_pex(&am_writer, &connector, &ext))
+        } else {
+            None
+        };
+        serve_peer(
+            &am_reader, &am_writer, &parser, &torrent, &field, &connector, &ext, &count,
         )
         .await;
+        if let Some(pex) = pex {
+            pex.abort();
+        }
+        if let Some(addr) = outgoing {
+            connector.swarm.lock().unwrap().disconnect(&addr);
+        }
+    })
+}
 
-        // resets in progress pieces
-        task::block_in_place(|| {
-            let mut f = field.lock().unwrap();
-            for i in &v {
-                if f.arr[*i] == IN_PROGRESS {
-                    f.arr[*i] = EMPTY;
-                    connector.piece.notify_one();
-                }
-            }
-            for i in &f.arr {
-                if *i == EMPTY {
-                    complete = false;
-                }
-            }
-        });
+// fetches pieces until there are none left, then seeds to the peer.
+#[allow(clippy::too_many_arguments)]
+async fn serve_peer(
+    am_reader: &Arc<TokioMutex<OwnedReadHalf>>,
+    am_writer: &Arc<TokioMutex<OwnedWriteHalf>>,
+    parser: &Arc<Parser>,
+    torrent: &Arc<Client>,
+    field: &Arc<Mutex<ByteField>>,
+    connector: &Arc<Connector>,
+    ext: &Arc<Mutex<PeerExt>>,
+    count: &Arc<AtomicU32>,
+) {
+    let mut complete = false;
+    task::block_in_place(|| {
+        let f = field.lock().unwrap();
+        if f.if_full() {
+            complete = true;
+        }
+    });
+    if complete {
         torrent_seeder(
-            &am_reader, &am_writer, &parser, &torrent, &field, &connector, &count,
+            am_reader, am_writer, parser, torrent, field, connector, ext, count,
         )
         .await;
+        return;
+    }
+    let v = torrent_fetcher(
+        am_reader, am_writer, parser, torrent, field, connector, ext, count,
+    )
+    .await;
+
+    // resets in progress pieces
+    task::block_in_place(|| {
+        let mut f = field.lock().unwrap();
+        for i in &v {
+            if f.arr[*i] == IN_PROGRESS {
+                f.arr[*i] = EMPTY;
+                connector.piece.notify_one();
+            }
+        }
+        for i in &f.arr {
+            if *i == EMPTY {
+                complete = false;
+            }
+        }
     });
+    torrent_seeder(
+        am_reader, am_writer, parser, torrent, field, connector, ext, count,
+    )
+    .await;
 }
@@ -0,0 +1,111 @@
+// BEP 10 extensions this client speaks, and routing of their messages.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use tokio::{net::tcp::OwnedWriteHalf, sync::Mutex as TokioMutex};
+
+use crate::torrent::Client;
+
+use super::{
+    connect::Connector,
+    metadata::{handle_metadata, UT_METADATA, UT_METADATA_ID},
+    msg::{structs::Extended, ExtHandshake, ExtRegistry},
+    pex::{handle_pex, PexPeer, UT_PEX, UT_PEX_ID},
+};
+
+// outstanding requests we advertise, peers shouldn't queue more than this with us.
+pub const REQQ: i64 = 250;
+
+// a registry with every extension we support, one per connection.
+pub fn registry() -> ExtRegistry {
+    let mut registry = ExtRegistry::default();
+    registry.register(UT_METADATA, UT_METADATA_ID);
+    registry.register(UT_PEX, UT_PEX_ID);
+    registry
+}
+
+// extension state of one connection, shared by its message handler and pex timer.
+#[derive(Debug, Default)]
+pub struct PeerExt {
+    pub registry: ExtRegistry,
+    pub pex: PexPeer,
+}
+
+impl PeerExt {
+    // addr is the peer's address, left out of the pex messages it gets.
+    pub fn new(addr: Option<SocketAddr>) -> Self {
+        Self {
+            registry: registry(),
+            pex: PexPeer::new(addr),
+        }
+    }
+}
+
+// our extended handshake, yourip is the peer's address as we see it.
+pub fn ext_handshake(
+    torrent: &Client,
+    registry: &ExtRegistry,
+    port: u16,
+    yourip: Option<IpAddr>,
+) -> ExtHandshake {
+    let mut handshake = registry.handshake();
+    // BEP 27, private torrents only get peers from their trackers.
+    if torrent.metainfo.private() {
+        handshake.m.remove(UT_PEX);
+    }
+    ExtHandshake {
+        v: Some(format!(
+            "{}/{}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        )),
+        p: if port > 0 { Some(port) } else { None },
+        reqq: Some(REQQ),
+        yourip: yourip.map(|ip| match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        }),
+        // advertise the info dict once we have it.
+        metadata_size: match torrent.metainfo.info_bytes().len() {
+            0 => None,
+            len => Some(len),
+        },
+        ..handshake
+    }
+}
+
+// handles an extended message from a peer, handshakes update the registry and
+// everything else goes to the extension its id was registered for.
+pub async fn handle_extended(
+    write: &Arc<TokioMutex<OwnedWriteHalf>>,
+    torrent: &Arc<Client>,
+    connector: &Arc<Connector>,
+    state: &Arc<Mutex<PeerExt>>,
+    ext: &Extended,
+) -> Option<()> {
+    let (name, remote_metadata) = {
+        let mut state = state.lock().unwrap();
+        if ext.ext_id == 0 {
+            if let Some(handshake) = ExtHandshake::parse(&ext.payload) {
+                state.registry.update(handshake);
+            }
+            return Some(());
+        }
+        (
+            state.registry.local_name(ext.ext_id).map(str::to_string),
+            state.registry.remote_id(UT_METADATA),
+        )
+    };
+    match name.as_deref() {
+        Some(UT_METADATA) => handle_metadata(write, torrent, remote_metadata, &ext.payload).await,
+        Some(UT_PEX) if !torrent.metainfo.private() => {
+            handle_pex(connector, &mut state.lock().unwrap().pex, &ext.payload);
+            Some(())
+        }
+        // not ours, or an id we never handed out.
+        _ => Some(()),
+    }
+}
@@ -2,7 +2,8 @@
 #![allow(dead_code)]
 
 use super::{
-    msg::{bytes::*, structs::*, SUBPIECE_LEN},
+    extension::{handle_extended, PeerExt},
+    msg::{bytes::*, structs::*, Message, SUBPIECE_LEN},
     parse::Parser,
     Connector,
 };
@@ -17,7 +18,7 @@ use std::{
         atomic::{AtomicU32, Ordering},
         Arc, Mutex,
     },
-    usize, vec,
+    vec,
 };
 
 use tokio::{
@@ -42,12 +43,11 @@ async fn request_piece(
         ..Request::default()
     };
 
-    let remainder;
-    if index as usize == torrent.num_pieces - 1 {
-        remainder = torrent.file_len % torrent.piece_len;
+    let remainder = if index as usize == torrent.num_pieces - 1 {
+        torrent.file_len % torrent.piece_len
     } else {
-        remainder = torrent.piece_len;
-    }
+        torrent.piece_len
+    };
 
     let mut num_subpieces = remainder / SUBPIECE_LEN as usize;
     for i in 0..num_subpieces {
@@ -79,6 +79,7 @@ async fn request_piece(
     Some(num_subpieces)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn read_piece(
     read: &Arc<TokioMutex<OwnedReadHalf>>,
     write: &Arc<TokioMutex<OwnedWriteHalf>>,
@@ -86,6 +87,7 @@ async fn read_piece(
     torrent: &Arc<Client>,
     field: &Arc<Mutex<ByteField>>,
     connector: &Arc<Connector>,
+    ext: &Arc<Mutex<PeerExt>>,
     count: &Arc<AtomicU32>,
     num_subpieces: usize,
 ) -> Option<()> {
@@ -105,16 +107,23 @@ async fn read_piece(
     let connector = Arc::clone(connector);
     let count = Arc::clone(count);
     let subf = Arc::clone(&am_subfield);
+    let ext_connector = Arc::clone(&connector);
+    let ext_state = Arc::clone(ext);
 
     let seeder: JoinHandle<Option<()>> = task::spawn(async move {
         loop {
-            let req = match req_rx.recv().await {
+            let msg = match req_rx.recv().await {
                 Ok(r) => r,
                 Err(_) => return Some(()),
             };
-            match fulfill_req(&write, &torrent, &field, &count, &req).await {
-                Some(_) => continue,
-                None => return None,
+            match msg {
+                Message::Request(req) => {
+                    fulfill_req(&write, &torrent, &field, &ext_connector, &count, &req).await?
+                }
+                Message::Extended(ext) => {
+                    handle_extended(&write, &torrent, &ext_connector, &ext_state, &ext).await?
+                }
+                _ => {}
             }
         }
     });
@@ -130,7 +139,7 @@ async fn read_piece(
                 if subf.if_full() {
                     return None;
                 }
-                return Some(());
+                Some(())
             })
             .is_none()
             {
@@ -149,7 +158,7 @@ async fn read_piece(
             }
         }
         drop(byte_tx);
-        return Some(());
+        Some(())
     });
 
     let item = ParseItem {
@@ -163,10 +172,11 @@ async fn read_piece(
 
     seeder.await.unwrap()?;
 
-    return Some(());
+    Some(())
 }
 
 // represents a single connection to a peer, continously fetches subpieces
+#[allow(clippy::too_many_arguments)]
 pub async fn torrent_fetcher(
     read: &Arc<TokioMutex<OwnedReadHalf>>,
     write: &Arc<TokioMutex<OwnedWriteHalf>>,
@@ -174,6 +184,7 @@ pub async fn torrent_fetcher(
     torrent: &Arc<Client>,
     field: &Arc<Mutex<ByteField>>,
     connector: &Arc<Connector>,
+    ext: &Arc<Mutex<PeerExt>>,
     count: &Arc<AtomicU32>,
 ) -> Vec<usize> {
     let mut idxs = vec![];
@@ -199,9 +210,9 @@ pub async fn torrent_fetcher(
                     .unwrap();
                 if let Some(p) = pf.get_empty() {
                     pf.arr[p] = IN_PROGRESS;
-                    return Some(p);
+                    Some(p)
                 } else {
-                    return None;
+                    None
                 }
             }) {
                 Some(p) => p,
@@ -220,12 +231,13 @@ pub async fn torrent_fetcher(
         }
         for num_subpieces in nums {
             if read_piece(
-                &read,
-                &write,
+                read,
+                write,
                 parser,
                 torrent,
                 field,
                 connector,
+                ext,
                 count,
                 num_subpieces,
             )
@@ -0,0 +1,488 @@
+// BEP 9 ut_metadata, fetching the info dict for magnet links and serving ours.
+
+use std::{collections::HashSet, net::SocketAddr, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::{mpsc, Mutex as TokioMutex},
+    task,
+    time::timeout,
+};
+
+use crate::{
+    bencode::{de::from_item, decode::parse_prefix, to_bytes},
+    dht::{bootstrap_addrs, Dht},
+    torrent::Client,
+    tracker::{announce_urls, get_info_hash, get_info_hash_v2, AnnounceRequest, TrackerList},
+};
+
+use super::{
+    connect::Connector,
+    extension::registry,
+    msg::{
+        bytes::EXTENDED,
+        parse_u32,
+        structs::{Extended, Handshake},
+        ExtHandshake, RESERVED_EXTENSION,
+    },
+};
+
+pub const UT_METADATA: &str = "ut_metadata";
+// the id we ask peers to send ut_metadata messages with.
+pub const UT_METADATA_ID: u8 = 1;
+// metadata moves in 16 KiB pieces, the last one may be shorter.
+pub const METADATA_PIECE_LEN: usize = 0x4000;
+// info dicts claiming to be larger than this are refused.
+pub const MAX_METADATA_SIZE: usize = 1 << 24;
+// time given to a single peer to hand over the whole info dict.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMsg {
+    Request(usize),
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject(usize),
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawMetadataMsg {
+    msg_type: i64,
+    piece: usize,
+    total_size: Option<usize>,
+}
+
+impl MetadataMsg {
+    // a bencoded dict, data messages carry the piece right after it.
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let (item, len) = parse_prefix(payload).ok()?;
+        let raw: RawMetadataMsg = from_item(item).ok()?;
+        match raw.msg_type {
+            0 => Some(MetadataMsg::Request(raw.piece)),
+            1 => Some(MetadataMsg::Data {
+                piece: raw.piece,
+                total_size: raw.total_size?,
+                data: payload[len..].to_vec(),
+            }),
+            2 => Some(MetadataMsg::Reject(raw.piece)),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let (msg_type, piece, total_size) = match *self {
+            MetadataMsg::Request(p) => (0, p, None),
+            MetadataMsg::Data {
+                piece, total_size, ..
+            } => (1, piece, Some(total_size)),
+            MetadataMsg::Reject(p) => (2, p, None),
+        };
+        let mut bytes = to_bytes(&RawMetadataMsg {
+            msg_type,
+            piece,
+            total_size,
+        })
+        .unwrap();
+        if let MetadataMsg::Data { data, .. } = self {
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+}
+
+// collects metadata pieces until the whole info dict is here.
+pub struct MetadataBuf {
+    size: usize,
+    pieces: Vec<Option<Vec<u8>>>,
+}
+
+impl MetadataBuf {
+    pub fn new(size: usize) -> Option<Self> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return None;
+        }
+        Some(MetadataBuf {
+            size,
+            pieces: vec![None; size.div_ceil(METADATA_PIECE_LEN)],
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len()
+    }
+
+    // false for pieces out of range or of the wrong length.
+    pub fn add(&mut self, piece: usize, data: &[u8]) -> bool {
+        if piece >= self.pieces.len() {
+            return false;
+        }
+        let expected = (self.size - piece * METADATA_PIECE_LEN).min(METADATA_PIECE_LEN);
+        if data.len() != expected {
+            return false;
+        }
+        self.pieces[piece] = Some(data.to_vec());
+        true
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    // the assembled dict, if it hashes to info_hash as v1 or truncated v2.
+    pub fn finish(&self, info_hash: &[u8; 20]) -> Option<Vec<u8>> {
+        let mut info = Vec::with_capacity(self.size);
+        for p in &self.pieces {
+            info.extend_from_slice(p.as_ref()?);
+        }
+        if get_info_hash(&info) == *info_hash || get_info_hash_v2(&info)[..20] == info_hash[..] {
+            Some(info)
+        } else {
+            None
+        }
+    }
+}
+
+// answers a metadata request from our info dict, rejecting while we have none.
+pub fn serve(info: &[u8], msg: &MetadataMsg) -> Option<MetadataMsg> {
+    let piece = match *msg {
+        MetadataMsg::Request(p) => p,
+        _ => return None,
+    };
+    let start = piece.saturating_mul(METADATA_PIECE_LEN);
+    if start >= info.len() {
+        return Some(MetadataMsg::Reject(piece));
+    }
+    let end = (start + METADATA_PIECE_LEN).min(info.len());
+    Some(MetadataMsg::Data {
+        piece,
+        total_size: info.len(),
+        data: info[start..end].to_vec(),
+    })
+}
+
+// answers a peer's ut_metadata message, remote_id is the id the peer asked
+// ut_metadata to be sent with.
+pub async fn handle_metadata(
+    write: &Arc<TokioMutex<OwnedWriteHalf>>,
+    torrent: &Arc<Client>,
+    remote_id: Option<u8>,
+    payload: &[u8],
+) -> Option<()> {
+    let (id, msg) = match (remote_id, MetadataMsg::parse(payload)) {
+        (Some(id), Some(msg)) => (id, msg),
+        _ => return Some(()),
+    };
+    if let Some(reply) = serve(torrent.metainfo.info_bytes(), &msg) {
+        let bytes = Extended::new(id, reply.as_bytes()).as_bytes();
+        let mut strm = write.lock().await;
+        strm.write_all(&bytes).await.ok()?;
+    }
+    Some(())
+}
+
+// reads one length prefixed peer wire message, keep alives are skipped.
+async fn read_msg(stream: &mut TcpStream) -> Option<Vec<u8>> {
+    loop {
+        let mut msg = vec![0u8; 4];
+        stream.read_exact(&mut msg).await.ok()?;
+        let len = parse_u32(&msg) as usize;
+        if len == 0 {
+            continue;
+        }
+        if len > MAX_METADATA_SIZE {
+            return None;
+        }
+        msg.resize(4 + len, 0);
+        stream.read_exact(&mut msg[4..]).await.ok()?;
+        return Some(msg);
+    }
+}
+
+async fn fetch(addr: SocketAddr, info_hash: [u8; 20], peer_id: [u8; 20]) -> Option<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr).await.ok()?;
+    let handshake = Handshake {
+        info_hash,
+        peer_id,
+        ..Handshake::default()
+    };
+    let mut registry = registry();
+    let mut out = bincode::serialize(&handshake).unwrap();
+    out.extend_from_slice(&Extended::new(0, registry.handshake().as_bytes()).as_bytes());
+    stream.write_all(&out).await.ok()?;
+
+    let mut buf = vec![0u8; 68];
+    stream.read_exact(&mut buf).await.ok()?;
+    let theirs = Handshake::parse(&mut buf)?;
+    if theirs.reserved[5] & RESERVED_EXTENSION == 0 || theirs.info_hash != info_hash {
+        return None;
+    }
+
+    let mut metadata: Option<MetadataBuf> = None;
+    loop {
+        let mut msg = read_msg(&mut stream).await?;
+        if msg[4] != EXTENDED {
+            continue;
+        }
+        let ext = Extended::parse(&mut msg)?;
+        if ext.ext_id == 0 {
+            let handshake = ExtHandshake::parse(&ext.payload)?;
+            let size = handshake.metadata_size;
+            registry.update(handshake);
+            let id = registry.remote_id(UT_METADATA)?;
+            let buf = MetadataBuf::new(size?)?;
+            let mut requests = vec![];
+            for piece in 0..buf.num_pieces() {
+                let req = Extended::new(id, MetadataMsg::Request(piece).as_bytes());
+                requests.extend_from_slice(&req.as_bytes());
+            }
+            stream.write_all(&requests).await.ok()?;
+            metadata = Some(buf);
+        } else if registry.local_name(ext.ext_id) == Some(UT_METADATA) {
+            let buf = metadata.as_mut()?;
+            match MetadataMsg::parse(&ext.payload)? {
+                MetadataMsg::Data {
+                    piece,
+                    total_size,
+                    data,
+                } if total_size == buf.size() => {
+                    if !buf.add(piece, &data) {
+                        return None;
+                    }
+                    if buf.is_complete() {
+                        return buf.finish(&info_hash);
+                    }
+                }
+                // a reject, or a peer that changed its mind about the size.
+                _ => return None,
+            }
+        }
+    }
+}
+
+// fetches and verifies the info dict from a single peer.
+pub async fn fetch_metadata(
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+) -> Option<Vec<u8>> {
+    timeout(FETCH_TIMEOUT, fetch(addr, info_hash, peer_id))
+        .await
+        .ok()?
+}
+
+// asks known, tracker, dht and lsd peers for the info dict until one hands over
+// a valid one, returning the client with its files created.
+pub async fn fetch_info(
+    client: &Client,
+    port: u16,
+    dht: Option<&Arc<Dht>>,
+    connector: &Connector,
+) -> Option<Client> {
+    // every tier and the dht report peers here as soon as they have them, so a
+    // dead tracker doesn't hold up the rest.
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let req = AnnounceRequest {
+        // the size is unknown until the info dict arrives, but we're no seed.
+        left: 1,
+        ..AnnounceRequest::new(client.info_hash, client.peer_id, port)
+    };
+    for urls in TrackerList::new(&client.metainfo).tiers() {
+        let (urls, req, tx) = (urls.clone(), req.clone(), tx.clone());
+        task::spawn(async move {
+            match announce_urls(&urls, &req).await {
+                Ok((resp, _)) => {
+                    let _ = tx.send(resp.peers);
+                }
+                Err(e) => eprintln!("{}", e),
+            }
+        });
+    }
+    // a magnet link without trackers has only the dht, which has to join first.
+    if let (Some(node), Some(config)) = (dht, &client.dht) {
+        let (node, config, tx) = (Arc::clone(node), config.clone(), tx.clone());
+        let info_hash = client.info_hash;
+        task::spawn(async move {
+            node.bootstrap(&bootstrap_addrs(&config).await).await;
+            let _ = tx.send(node.get_peers(info_hash).await);
+        });
+    }
+    drop(tx);
+
+    let mut seen = HashSet::new();
+    if let Some(c) = fetch_from(client, client.peers.clone(), &mut seen).await {
+        return Some(c);
+    }
+    while let Some(peers) = rx.recv().await {
+        if let Some(c) = fetch_from(client, peers, &mut seen).await {
+            return Some(c);
+        }
+    }
+    // lsd peers heard of meanwhile stay queued, the main loop dials them too.
+    let lsd = connector.swarm.lock().unwrap().discovered().to_vec();
+    fetch_from(client, lsd, &mut seen).await
+}
+
+// tries peers not in seen one at a time, the client once one sends a valid info dict.
+async fn fetch_from(
+    client: &Client,
+    peers: Vec<SocketAddr>,
+    seen: &mut HashSet<SocketAddr>,
+) -> Option<Client> {
+    for addr in peers {
+        if !seen.insert(addr) {
+            continue;
+        }
+        let info = match fetch_metadata(addr, client.info_hash, client.peer_id).await {
+            Some(i) => i,
+            None => continue,
+        };
+        match client.with_info(&info).await {
+            Ok(c) => return Some(c),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod metadata_test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // 40000 bytes of info dict, three metadata pieces.
+    fn info_dict() -> Vec<u8> {
+        let pieces = vec![b'a'; 39942];
+        let mut info = format!(
+            "d6:lengthi1e4:name1:x12:piece lengthi16384e6:pieces{}:",
+            pieces.len()
+        )
+        .into_bytes();
+        info.extend_from_slice(&pieces);
+        info.push(b'e');
+        info
+    }
+
+    // a peer that hands out `info` over ut_metadata for whatever info hash it's
+    // asked for, rejecting piece `reject`.
+    async fn mock_peer(info: Vec<u8>, reject: Option<usize>) -> SocketAddr {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 68];
+            stream.read_exact(&mut buf).await.unwrap();
+            let handshake = Handshake::parse(&mut buf).unwrap();
+            assert_eq!(handshake.peer_id, [7; 20]);
+            let ours = ExtHandshake {
+                m: [(UT_METADATA.to_string(), 3)].into(),
+                metadata_size: Some(info.len()),
+                ..ExtHandshake::default()
+            };
+            let mut out = bincode::serialize(&handshake).unwrap();
+            out.extend_from_slice(&Extended::new(0, ours.as_bytes()).as_bytes());
+            stream.write_all(&out).await.unwrap();
+
+            let mut remote_id = None;
+            while let Some(mut msg) = read_msg(&mut stream).await {
+                let ext = Extended::parse(&mut msg).unwrap();
+                if ext.ext_id == 0 {
+                    let theirs = ExtHandshake::parse(&ext.payload).unwrap();
+                    remote_id = theirs.m.get(UT_METADATA).map(|id| *id as u8);
+                    continue;
+                }
+                assert_eq!(ext.ext_id, 3);
+                let msg = MetadataMsg::parse(&ext.payload).unwrap();
+                let reply = match msg {
+                    MetadataMsg::Request(p) if Some(p) == reject => MetadataMsg::Reject(p),
+                    _ => serve(&info, &msg).unwrap(),
+                };
+                let out = Extended::new(remote_id.unwrap(), reply.as_bytes()).as_bytes();
+                stream.write_all(&out).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_msg() {
+        let data = MetadataMsg::Data {
+            piece: 2,
+            total_size: 40000,
+            data: b"abc".to_vec(),
+        };
+        assert_eq!(
+            data.as_bytes(),
+            b"d8:msg_typei1e5:piecei2e10:total_sizei40000eeabc"
+        );
+        assert_eq!(MetadataMsg::parse(&data.as_bytes()), Some(data));
+        let req = MetadataMsg::Request(1);
+        assert_eq!(req.as_bytes(), b"d8:msg_typei0e5:piecei1ee");
+        assert_eq!(MetadataMsg::parse(&req.as_bytes()), Some(req));
+        assert_eq!(MetadataMsg::parse(b"d8:msg_typei7e5:piecei1ee"), None);
+    }
+
+    #[test]
+    fn test_serve() {
+        let info = info_dict();
+        match serve(&info, &MetadataMsg::Request(2)) {
+            Some(MetadataMsg::Data {
+                piece,
+                total_size,
+                data,
+            }) => {
+                assert_eq!((piece, total_size), (2, 40000));
+                assert_eq!(data, info[2 * METADATA_PIECE_LEN..]);
+            }
+            _ => panic!("expected data"),
+        }
+        assert_eq!(
+            serve(&info, &MetadataMsg::Request(3)),
+            Some(MetadataMsg::Reject(3))
+        );
+        assert_eq!(
+            serve(&[], &MetadataMsg::Request(0)),
+            Some(MetadataMsg::Reject(0))
+        );
+    }
+
+    #[test]
+    fn test_fetch() {
+        tokio_test::block_on(async {
+            let info = info_dict();
+            let info_hash = get_info_hash(&info);
+            let addr = mock_peer(info.clone(), None).await;
+            assert_eq!(fetch_metadata(addr, info_hash, [7; 20]).await, Some(info));
+        });
+    }
+
+    #[test]
+    fn test_fetch_reject() {
+        tokio_test::block_on(async {
+            let info = info_dict();
+            let info_hash = get_info_hash(&info);
+            let addr = mock_peer(info, Some(1)).await;
+            assert_eq!(fetch_metadata(addr, info_hash, [7; 20]).await, None);
+        });
+    }
+
+    #[test]
+    fn test_fetch_bad_hash() {
+        tokio_test::block_on(async {
+            let info = info_dict();
+            let mut info_hash = get_info_hash(&info);
+            info_hash[0] ^= 1;
+            let addr = mock_peer(info, None).await;
+            // the peer answers for any hash, the assembled dict must still match.
+            assert_eq!(fetch_metadata(addr, info_hash, [7; 20]).await, None);
+        });
+    }
+}
@@ -1,30 +1,38 @@
 use tokio::{
     io::AsyncWriteExt,
-    net::{TcpListener, TcpStream},
+    net::{self, TcpListener, TcpStream},
     runtime::Handle,
+    signal,
+    sync::mpsc,
     task::{self, JoinHandle},
     time,
 };
 
 use crate::{
+    dht::Dht,
     field::{
         constant::{self, COMPLETE},
         ByteField,
     },
     file::resume_torrent,
     hash::{spawn_hash_write, Hasher},
+    lsd::Lsd,
     tcp_bt::{
         connect::{spawn_connecter_task, Connector},
+        metadata::fetch_info,
         msg::SUBPIECE_LEN,
         parse::{spawn_parsers, Parser},
         seed::{spawn_listener, Peer},
     },
     torrent::Client,
-    tracker::{announce, get_addr},
+    tracker::{announce_urls, AnnounceRequest, Event, Schedule, TrackerList},
 };
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::{atomic::AtomicU32, Arc, Mutex},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use self::msg::{
@@ -33,18 +41,28 @@ use self::msg::{
 };
 
 pub mod connect;
+pub mod extension;
 pub mod fetch;
+pub mod metadata;
 pub mod msg;
 pub mod parse;
+pub mod pex;
 pub mod seed;
 
+// 50ms apart, how long to wait for a peer's handshake to arrive in full.
+const HANDSHAKE_POLLS: usize = 100;
+// how long shutdown waits on each stopped announce, they're all sent at once.
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub async fn send_handshake(
     stream: &mut TcpStream,
     info_hash: [u8; 20],
     peer_id: [u8; 20],
+    reserved: [u8; 8],
 ) -> Option<()> {
     // make handshake
     let handshake = Handshake {
+        reserved,
         info_hash,
         peer_id,
         ..Handshake::default()
@@ -64,17 +82,143 @@ pub async fn send_handshake(
     Some(())
 }
 
+// waits for the peer's handshake without consuming it, the parser reads it later.
+pub async fn peek_handshake(stream: &TcpStream) -> Option<Handshake> {
+    let mut buf = vec![0u8; 68];
+    for _ in 0..HANDSHAKE_POLLS {
+        let n = stream.peek(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        if n >= 68 {
+            return Handshake::parse(&mut buf);
+        }
+        time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    None
+}
+
+// bytes of the pieces we have, the last piece may be short.
+fn completed_bytes(client: &Client, field: &ByteField) -> u64 {
+    let mut have = 0;
+    for (i, p) in field.arr.iter().enumerate() {
+        if *p == COMPLETE {
+            have += match i == client.num_pieces - 1 {
+                true => client.file_len - i * client.piece_len,
+                false => client.piece_len,
+            };
+        }
+    }
+    have as u64
+}
+
+// uploaded, downloaded and left, as trackers want them.
+fn counters(
+    client: &Client,
+    field: &Mutex<ByteField>,
+    parser: &Parser,
+    connector: &Connector,
+) -> (u64, u64, u64) {
+    let have = task::block_in_place(|| completed_bytes(client, &field.lock().unwrap()));
+    (
+        connector.uploaded.load(Ordering::Relaxed),
+        parser.downloaded.load(Ordering::Relaxed),
+        client.file_len as u64 - have,
+    )
+}
+
+// ctrl-c, or sigterm where there is one.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    if let Ok(mut term) = signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+        return;
+    }
+    let _ = signal::ctrl_c().await;
+}
+
 impl Client {
-    pub async fn start(self) {
+    pub async fn start(mut self) {
+        let listener = TcpListener::bind(("0.0.0.0", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // x.pe hostnames are resolved here, the magnet parser never blocks on dns.
+        for host in std::mem::take(&mut self.peer_hosts) {
+            match net::lookup_host(&host).await {
+                Ok(mut addrs) => self.peers.extend(addrs.next()),
+                Err(e) => eprintln!("can't resolve peer {}: {}", host, e),
+            }
+        }
+
+        let connector = Arc::new(Connector::new());
+        connector
+            .port
+            .store(port, std::sync::atomic::Ordering::Relaxed);
+
+        // the dht and lsd come up before any info dict is fetched, they're all a
+        // magnet link without trackers has to find peers with.
+        let mut dht = None;
+        if let Some(config) = &self.dht {
+            match Dht::start(config).await {
+                Ok(node) => {
+                    if let Ok(addr) = node.0.local_addr() {
+                        println!("dht listening on {}", addr);
+                    }
+                    dht = Some(node);
+                }
+                Err(e) => eprintln!("dht: {}", e),
+            }
+        }
+
+        // peers on the local network join the pex queue the main loop dials from.
+        let mut lsd = None;
+        if self.lsd {
+            match Lsd::bind() {
+                Ok(node) => {
+                    let found = Arc::clone(&connector);
+                    let listener = node.spawn_listener(self.metainfo.info_hashes(), move |peers| {
+                        found.swarm.lock().unwrap().discover(peers)
+                    });
+                    let announcer = node.spawn_announcer(self.metainfo.info_hashes(), port);
+                    lsd = Some((listener, announcer));
+                }
+                Err(e) => eprintln!("lsd: {}", e),
+            }
+        }
+
+        // magnet links get the info dict from peers before anything else.
+        if self.is_pending() {
+            let node = dht.as_ref().map(|(node, _)| node);
+            self = match fetch_info(&self, port, node, &connector).await {
+                Some(c) => c,
+                None => {
+                    eprintln!("no peer sent a valid info dict");
+                    return;
+                }
+            };
+        }
+
         let client = Arc::new(self);
-        let addr = get_addr(&client.tree).unwrap();
-        println!("the announce addr is: {}", addr);
+        println!("{}", client.metainfo);
+        // the info dict may show a magnet link was for a private torrent.
+        if let (false, Some((listener, announcer))) = (client.lsd, &lsd) {
+            listener.abort();
+            announcer.abort();
+            lsd = None;
+        }
+
+        let mut trackers = TrackerList::new(&client.metainfo);
+        for (i, tier) in trackers.tiers().iter().enumerate() {
+            println!("tracker tier {}: {}", i, tier.join(", "));
+        }
 
         // piece field;
         let field: Arc<Mutex<ByteField>> = Arc::new(Mutex::new(ByteField {
             arr: vec![constant::EMPTY; client.num_pieces],
         }));
-        let connector = Arc::new(Connector::new());
 
         // spawn hashing thread pool;
         let hasher = Arc::new(Hasher::new());
@@ -99,24 +243,75 @@ impl Client {
         let scount = Arc::new(AtomicU32::new(0));
         let mut conn_handles: Vec<JoinHandle<()>> = vec![];
 
-        let listener = TcpListener::bind(("0.0.0.0", 0)).await.unwrap();
-
-        let port = listener.local_addr().unwrap().port();
         let l_handle =
             spawn_listener(listener, &parser, &client, &field, &connector, &scount).await;
 
+        // peers known up front, i.e. x.pe from magnet links.
+        for addr in &client.peers {
+            conn_handles.push(
+                spawn_connecter_task(
+                    Peer::Addr(*addr),
+                    client.info_hash,
+                    &parser,
+                    &client,
+                    &field,
+                    &connector,
+                    &scount,
+                )
+                .await,
+            );
+        }
+
+        // peers the dht finds join the pex queue too.
+        let dht = match (dht, &client.dht) {
+            (Some((node, handle)), Some(config)) => {
+                let found = Arc::clone(&connector);
+                let announcer = node.spawn_announcer(
+                    config.clone(),
+                    client.metainfo.info_hashes(),
+                    port,
+                    move |peers| found.swarm.lock().unwrap().discover(peers),
+                );
+                Some((node, handle, announcer))
+            }
+            // private after all, see lsd above.
+            (Some((_, handle)), None) => {
+                handle.abort();
+                None
+            }
+            (None, _) => None,
+        };
+
         let tor = Arc::clone(&client);
         let num_subpieces = tor.piece_len / SUBPIECE_LEN as usize;
 
+        // every tier is announced to, hybrid torrents under both info hashes.
+        let mut schedules: Vec<(usize, [u8; 20], Schedule)> = vec![];
+        for tier in 0..trackers.tiers().len() {
+            for h in tor.metainfo.info_hashes() {
+                schedules.push((tier, h, Schedule::new(Instant::now())));
+            }
+        }
+
+        // each due announce runs as its own task and reports back here by schedule
+        // index, so a slow tracker never holds up the loop.
+        let (announce_tx, mut announce_rx) = mpsc::unbounded_channel();
+        let mut in_flight = vec![false; schedules.len()];
+
         // main loop control
         let mut seeded = 0_usize;
-        let mut counter = 0_usize;
-        const ANNOUNCE_INTERVAL: usize = 60 / LOOP_SLEEP as usize;
         const LOOP_SLEEP: usize = 1;
 
-        // shutdown when share ratio >= 1.
+        // resumed downloads that were already whole never send completed.
+        let mut full = task::block_in_place(|| field.lock().unwrap().if_full());
+
+        let interrupted = shutdown_signal();
+        tokio::pin!(interrupted);
+
+        // shutdown when share ratio >= 1, or on a signal.
         while seeded < tor.num_pieces {
             let mut prgoress = 0_usize;
+            let mut done = false;
             task::block_in_place(|| {
                 let pf = field.lock().unwrap();
                 for i in &pf.arr {
@@ -124,27 +319,75 @@ impl Client {
                         prgoress += 1;
                     }
                 }
+                done = pf.if_full();
             });
             print!("progress {}/{};", prgoress, tor.num_pieces);
             println!("seeded {}/{}", seeded, tor.num_pieces);
-            if counter % ANNOUNCE_INTERVAL == 0 {
-                let peers = match announce(addr, tor.info_hash, port).await {
-                    Ok(p) => p,
+            let now = Instant::now();
+            if done && !full {
+                full = true;
+                for (_, _, schedule) in schedules.iter_mut() {
+                    schedule.complete(now);
+                }
+            }
+            let due: Vec<usize> = (0..schedules.len())
+                .filter(|&i| !in_flight[i] && schedules[i].2.is_due(now))
+                .collect();
+            if !due.is_empty() {
+                let (uploaded, downloaded, left) = counters(&tor, &field, &parser, &connector);
+                for i in due {
+                    let (tier, info_hash, schedule) = &schedules[i];
+                    let req = AnnounceRequest {
+                        uploaded,
+                        downloaded,
+                        left,
+                        event: schedule.event(),
+                        tracker_id: schedule.tracker_id.clone(),
+                        ..AnnounceRequest::new(*info_hash, tor.peer_id, port)
+                    };
+                    let urls = trackers.tiers()[*tier].to_vec();
+                    let tx = announce_tx.clone();
+                    in_flight[i] = true;
+                    task::spawn(async move {
+                        let resp = announce_urls(&urls, &req).await;
+                        let _ = tx.send((i, resp.map(|(r, j)| (r, urls[j].clone(), j != 0))));
+                    });
+                }
+            }
+            while let Ok((i, resp)) = announce_rx.try_recv() {
+                in_flight[i] = false;
+                let (tier, info_hash, schedule) = &mut schedules[i];
+                let resp = match resp {
+                    Ok((r, url, switched)) => {
+                        trackers.promote_url(*tier, &url);
+                        // a tracker id only means something to the tracker that sent it.
+                        if switched {
+                            schedule.tracker_id = None;
+                        }
+                        r
+                    }
                     Err(e) => {
-                        eprintln!("{}", e);
-                        counter = 1;
+                        let retry = schedule.failure(Instant::now());
+                        eprintln!("tier {}: {}, retrying in {}s", tier, e, retry.as_secs());
                         continue;
                     }
                 };
-                for peer in peers {
-                    if peer.port == port {
+                schedule.success(&resp, Instant::now());
+                if let Some(w) = &resp.warning {
+                    eprintln!("tracker warning: {}", w);
+                }
+                if let (Some(seeds), Some(leechers)) = (resp.complete, resp.incomplete) {
+                    println!("swarm: {} seeds, {} leechers", seeds, leechers);
+                }
+                for peer in resp.peers {
+                    if peer.port() == port {
                         continue;
                     }
-                    let addr = SocketAddr::new(IpAddr::from(Ipv4Addr::from(peer.ip)), peer.port);
                     let connector = Arc::clone(&connector);
                     conn_handles.push(
                         spawn_connecter_task(
-                            Peer::Addr(addr),
+                            Peer::Addr(peer),
+                            *info_hash,
                             &parser,
                             &client,
                             &field,
@@ -155,15 +398,69 @@ impl Client {
                     );
                 }
             }
-            counter += 1;
-            time::sleep(std::time::Duration::from_secs(LOOP_SLEEP as u64)).await;
+            // peers other peers told us about over pex.
+            let found = connector.swarm.lock().unwrap().take_discovered();
+            for addr in found {
+                if addr.port() == port && addr.ip().is_loopback() {
+                    continue;
+                }
+                conn_handles.push(
+                    spawn_connecter_task(
+                        Peer::Addr(addr),
+                        client.info_hash,
+                        &parser,
+                        &client,
+                        &field,
+                        &connector,
+                        &scount,
+                    )
+                    .await,
+                );
+            }
+            tokio::select! {
+                _ = time::sleep(std::time::Duration::from_secs(LOOP_SLEEP as u64)) => {}
+                _ = &mut interrupted => {
+                    // stop serving peers now, trackers still hear we stopped below.
+                    connector.brk.store(true, Ordering::Relaxed);
+                    break;
+                }
+            }
             seeded = scount.load(std::sync::atomic::Ordering::Relaxed) as usize / num_subpieces;
-            if scount.load(std::sync::atomic::Ordering::Relaxed) as usize % num_subpieces > 0 {
+            if !(scount.load(std::sync::atomic::Ordering::Relaxed) as usize)
+                .is_multiple_of(num_subpieces)
+            {
                 seeded += 1;
             }
         }
         // shutdown
         println!("shutdown");
+        // tell the trackers we're leaving, without waiting long on slow ones.
+        let (uploaded, downloaded, left) = counters(&tor, &field, &parser, &connector);
+        let mut stopped = vec![];
+        for (tier, info_hash, schedule) in &schedules {
+            if !schedule.is_started() {
+                continue;
+            }
+            let req = AnnounceRequest {
+                uploaded,
+                downloaded,
+                left,
+                event: Event::Stopped,
+                tracker_id: schedule.tracker_id.clone(),
+                ..AnnounceRequest::new(*info_hash, tor.peer_id, port)
+            };
+            let (tier, urls) = (*tier, trackers.tiers()[*tier].to_vec());
+            stopped.push(task::spawn(async move {
+                match time::timeout(STOPPED_TIMEOUT, announce_urls(&urls, &req)).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => eprintln!("tier {}: {}", tier, e),
+                    Err(_) => eprintln!("tier {}: stopped announce to {} timed out", tier, urls[0]),
+                }
+            }));
+        }
+        for handle in stopped {
+            let _ = handle.await;
+        }
         // break hasher loops
         hasher.brk.store(true, std::sync::atomic::Ordering::Relaxed);
         hasher.loops.notify_all();
@@ -185,5 +482,19 @@ impl Client {
         });
         l_handle.abort();
         let _ = l_handle.await;
+        if let Some((listener, announcer)) = lsd {
+            listener.abort();
+            announcer.abort();
+        }
+        // keep the nodes we know for the next run.
+        if let Some((node, handle, announcer)) = dht {
+            announcer.abort();
+            handle.abort();
+            if let Some(path) = client.dht.as_ref().and_then(|c| c.cache.as_ref()) {
+                if let Err(e) = node.save_nodes(path) {
+                    eprintln!("dht: {}", e);
+                }
+            }
+        }
     } // need to abort hanging threads
 }
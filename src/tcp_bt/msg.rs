@@ -11,6 +11,8 @@ pub mod bytes {
     pub const REQUEST: u8 = 6;
     pub const PIECE: u8 = 7;
     pub const CANCEL: u8 = 8;
+    // BEP 10 extension messages, the payload starts with an extended message id.
+    pub const EXTENDED: u8 = 20;
     pub const HANDSHAKE: u8 = 0x54;
 }
 
@@ -26,7 +28,7 @@ pub mod structs {
     use serde::Serialize;
 
     use super::{
-        bytes::{BITFIELD, CANCEL, HANDSHAKE, HAVE, PIECE, REQUEST},
+        bytes::{BITFIELD, CANCEL, EXTENDED, HANDSHAKE, HAVE, PIECE, REQUEST},
         parse_u32,
     };
     #[derive(Debug, Serialize)]
@@ -42,11 +44,14 @@ pub mod structs {
         fn default() -> Self {
             let name = "BitTorrent protocol";
             let mut p = [0u8; 19];
-            p.copy_from_slice(&name.as_bytes());
+            p.copy_from_slice(name.as_bytes());
+            // we always speak the extension protocol.
+            let mut reserved = [0u8; 8];
+            reserved[5] |= super::RESERVED_EXTENSION;
             Handshake {
                 pstrlen: 19,
                 pstr: p,
-                reserved: [0u8; 8],
+                reserved,
                 info_hash: [0u8; 20],
                 peer_id: [0u8; 20],
             }
@@ -94,7 +99,7 @@ pub mod structs {
 
     impl Header {
         pub fn test(&self) -> bool {
-            self.id <= CANCEL || self.id == HANDSHAKE
+            self.id <= CANCEL || self.id == EXTENDED || self.id == HANDSHAKE
         }
 
         pub fn parse(msg: &[u8]) -> Option<Self> {
@@ -168,7 +173,7 @@ pub mod structs {
         }
         pub fn parse(msg: &mut Vec<u8>) -> Option<Self> {
             let mut bitfield = Bitfield {
-                header: Header::parse(&msg)?,
+                header: Header::parse(msg)?,
                 ..Bitfield::default()
             };
             if msg.len() < (bitfield.header.len + 4) as usize {
@@ -179,9 +184,9 @@ pub mod structs {
                 .extend_from_slice(&msg[5..(bitfield.header.len + 4) as usize]);
             if bitfield.test() {
                 // msg.drain(0..(bitfield.header.len + 4) as usize);
-                let mut copy = &mut msg[((bitfield.header.len + 4) as usize)..].to_vec();
+                let copy = &mut msg[((bitfield.header.len + 4) as usize)..].to_vec();
                 msg.clear();
-                msg.append(&mut copy);
+                msg.append(copy);
                 Some(bitfield)
             } else {
                 None
@@ -244,7 +249,7 @@ pub mod structs {
 
         pub fn parse(msg: &mut Vec<u8>) -> Option<Self> {
             let mut piece = Piece {
-                header: Header::parse(&msg)?,
+                header: Header::parse(msg)?,
                 ..Piece::default()
             };
 
@@ -255,17 +260,7 @@ pub mod structs {
             piece.index = parse_u32(&msg[5..9]);
             piece.begin = parse_u32(&msg[9..13]);
 
-            // !understand following lines
-
-            unsafe {
-                piece.data = Vec::new();
-                piece.data.reserve((piece.header.len - 9) as usize);
-                piece.data.set_len((piece.header.len - 9) as usize);
-                let src = msg.as_ptr().add(13);
-                let dst = piece.data.as_mut_ptr();
-                std::ptr::copy_nonoverlapping(src, dst, (piece.header.len - 9) as usize);
-                piece.data.set_len((piece.header.len - 9) as usize);
-            }
+            piece.data = msg[13..(piece.header.len + 4) as usize].to_vec();
 
             if piece.test() {
                 let x = msg.len() - (piece.header.len + 4) as usize;
@@ -295,6 +290,61 @@ pub mod structs {
         }
     }
 
+    #[derive(Debug, Default, Clone)]
+    // https://www.bittorrent.org/beps/bep_0010.html
+    pub struct Extended {
+        pub header: Header,
+        // 0 for the extended handshake, otherwise an id from a handshake's `m`.
+        pub ext_id: u8,
+        pub payload: Vec<u8>,
+    }
+
+    impl Extended {
+        pub fn new(ext_id: u8, payload: Vec<u8>) -> Self {
+            Extended {
+                header: Header {
+                    len: payload.len() as u32 + 2,
+                    id: EXTENDED,
+                },
+                ext_id,
+                payload,
+            }
+        }
+
+        pub fn test(&self) -> bool {
+            if self.header.id != EXTENDED {
+                return false;
+            }
+            self.header.len as usize == self.payload.len() + 2
+        }
+
+        pub fn parse(msg: &mut Vec<u8>) -> Option<Self> {
+            let header = Header::parse(msg)?;
+            let end = header.len as usize + 4;
+            if header.len < 2 || msg.len() < end {
+                return None;
+            }
+            let ext = Extended {
+                ext_id: msg[5],
+                payload: msg[6..end].to_vec(),
+                header,
+            };
+            if ext.test() {
+                msg.drain(0..end);
+                Some(ext)
+            } else {
+                None
+            }
+        }
+
+        pub fn as_bytes(&self) -> Vec<u8> {
+            let mut bytes = self.header.as_bytes();
+            bytes.push(self.ext_id);
+            bytes.extend_from_slice(&self.payload);
+            bytes
+        }
+    }
+
     #[derive(Debug, Default, Serialize)]
     // https://wiki.theory.org/BitTorrentSpecification#Cancel
     pub struct Cancel {
@@ -332,9 +382,100 @@ pub mod structs {
     }
 }
 use self::{bytes::*, structs::*};
+use crate::bencode::{from_bytes, to_bytes};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 pub const SUBPIECE_LEN: u32 = 0x4000; // 2^14 = 16384
 
+// set in the last reserved byte by peers that support BEP 52 v2 torrents.
+pub const RESERVED_V2: u8 = 0x10;
+// set in reserved byte 5 by peers that speak the BEP 10 extension protocol.
+pub const RESERVED_EXTENSION: u8 = 0x10;
+
+// BEP 10 extended handshake, sent as extended message 0.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtHandshake {
+    // extension name -> the id the sender wants it sent with, 0 disables it.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    // client name and version.
+    pub v: Option<String>,
+    // the sender's listen port.
+    pub p: Option<u16>,
+    // how many outstanding requests the sender queues.
+    pub reqq: Option<i64>,
+    // the receiver's ip as the sender sees it, 4 or 16 bytes.
+    #[serde(default, with = "serde_bytes")]
+    pub yourip: Option<Vec<u8>>,
+    // size of the info dict, for ut_metadata.
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtHandshake {
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        from_bytes(payload).ok()
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        to_bytes(self).unwrap()
+    }
+}
+
+// per connection map of extension names to message ids, ours are the ids peers
+// send to us with, theirs the ids we send to them with.
+#[derive(Debug, Default, Clone)]
+pub struct ExtRegistry {
+    local: BTreeMap<String, u8>,
+    remote: BTreeMap<String, u8>,
+    // the peer's last extended handshake.
+    pub peer: Option<ExtHandshake>,
+}
+
+impl ExtRegistry {
+    pub fn register(&mut self, name: &str, id: u8) {
+        assert!(id != 0, "extended message id 0 is the handshake");
+        self.local.insert(name.to_string(), id);
+    }
+
+    // the extension a message sent to us belongs to.
+    pub fn local_name(&self, id: u8) -> Option<&str> {
+        self.local
+            .iter()
+            .find(|(_, v)| **v == id)
+            .map(|(k, _)| k.as_str())
+    }
+
+    // none until the peer's handshake says it supports the extension.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote.get(name).copied()
+    }
+
+    // applies a peer's handshake, handshakes may be resent and only carry changes,
+    // an id of 0 turns an extension off.
+    pub fn update(&mut self, handshake: ExtHandshake) {
+        for (name, id) in &handshake.m {
+            match u8::try_from(*id) {
+                Ok(0) | Err(_) => self.remote.remove(name),
+                Ok(id) => self.remote.insert(name.clone(), id),
+            };
+        }
+        self.peer = Some(handshake);
+    }
+
+    // our handshake with `m` filled from the registered extensions.
+    pub fn handshake(&self) -> ExtHandshake {
+        ExtHandshake {
+            m: self
+                .local
+                .iter()
+                .map(|(k, v)| (k.clone(), *v as i64))
+                .collect(),
+            ..ExtHandshake::default()
+        }
+    }
+}
+
 // enum for each type message
 pub enum Message {
     Handshake(Handshake),
@@ -347,6 +488,7 @@ pub enum Message {
     Request(Request),
     Piece(Piece),
     Cancel(Cancel),
+    Extended(Extended),
 }
 
 fn is_zero(msg: &[u8]) -> bool {
@@ -369,7 +511,7 @@ fn parse_msg(msg: &'static mut Vec<u8>) -> Vec<Message> {
         if is_zero(msg) {
             break;
         }
-        let _byte = match msg.get(0) {
+        let _byte = match msg.first() {
             Some(byte) => *byte,
             None => break,
         };
@@ -389,6 +531,7 @@ fn parse_msg(msg: &'static mut Vec<u8>) -> Vec<Message> {
             REQUEST => messages.push(Message::Request(Request::parse(msg).unwrap())),
             PIECE => messages.push(Message::Piece(Piece::parse(msg).unwrap())),
             CANCEL => messages.push(Message::Cancel(Cancel::parse(msg).unwrap())),
+            EXTENDED => messages.push(Message::Extended(Extended::parse(msg).unwrap())),
             _ => {
                 // println!("{:?}", msg);
                 unreachable!("parse message");
@@ -409,7 +552,7 @@ fn try_parse(orignal: &[u8]) -> bool {
             return false;
         }
 
-        let _byte = match msg.get(0) {
+        let _byte = match msg.first() {
             Some(byte) => *byte,
             None => return false,
         };
@@ -450,6 +593,11 @@ fn try_parse(orignal: &[u8]) -> bool {
                     return false;
                 }
             }
+            EXTENDED => {
+                if Extended::parse(&mut msg).is_none() {
+                    return false;
+                }
+            }
             HANDSHAKE => {
                 if Handshake::parse(&mut msg).is_none() {
                     return false;
@@ -469,11 +617,11 @@ pub fn partial_parse(msg: &mut Vec<u8>) -> (bool, Vec<Message>) {
         return (false, list);
     }
     loop {
-        if is_zero(&msg) {
+        if is_zero(msg) {
             return (false, list);
         }
 
-        let _byte = match msg.get(0) {
+        let _byte = match msg.first() {
             Some(byte) => *byte,
             None => return (true, list),
         };
@@ -484,28 +632,28 @@ pub fn partial_parse(msg: &mut Vec<u8>) -> (bool, Vec<Message>) {
         };
 
         match byte {
-            CHOKE => match Header::parse(&msg) {
+            CHOKE => match Header::parse(msg) {
                 Some(x) => {
                     msg.drain(0..5);
                     list.push(Message::Choke(x))
                 }
                 None => return (false, list),
             },
-            UNCHOKE => match Header::parse(&msg) {
+            UNCHOKE => match Header::parse(msg) {
                 Some(x) => {
                     msg.drain(0..5);
                     list.push(Message::Unchoke(x))
                 }
                 None => return (false, list),
             },
-            INTERESTED => match Header::parse(&msg) {
+            INTERESTED => match Header::parse(msg) {
                 Some(x) => {
                     msg.drain(0..5);
                     list.push(Message::Interested(x))
                 }
                 None => return (false, list),
             },
-            NOT_INTERESTED => match Header::parse(&msg) {
+            NOT_INTERESTED => match Header::parse(msg) {
                 Some(x) => {
                     msg.drain(0..5);
                     list.push(Message::NotInterested(x))
@@ -532,6 +680,10 @@ pub fn partial_parse(msg: &mut Vec<u8>) -> (bool, Vec<Message>) {
                 Some(x) => list.push(Message::Cancel(x)),
                 None => return (false, list),
             },
+            EXTENDED => match Extended::parse(msg) {
+                Some(x) => list.push(Message::Extended(x)),
+                None => return (false, list),
+            },
             HANDSHAKE => match Handshake::parse(msg) {
                 Some(x) => list.push(Message::Handshake(x)),
                 None => return (false, list),
@@ -543,3 +695,80 @@ pub fn partial_parse(msg: &mut Vec<u8>) -> (bool, Vec<Message>) {
         }
     }
 }
+
+#[cfg(test)]
+mod msg_test {
+    use super::*;
+
+    #[test]
+    fn test_partial_parse_extended() {
+        let ext = Extended::new(3, b"d1:ai1ee".to_vec());
+        let mut buf = ext.as_bytes();
+        buf.extend_from_slice(&[0, 0, 0, 5, HAVE, 0, 0, 0, 7]);
+        let (_, msgs) = partial_parse(&mut buf);
+        assert_eq!(msgs.len(), 2);
+        match &msgs[0] {
+            Message::Extended(e) => {
+                assert_eq!(e.ext_id, 3);
+                assert_eq!(e.payload, b"d1:ai1ee");
+            }
+            _ => panic!("expected extended message"),
+        }
+        assert!(matches!(msgs[1], Message::Have(ref h) if h.index == 7));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_ext_handshake() {
+        let handshake = ExtHandshake {
+            m: [("ut_metadata".to_string(), 1)].into(),
+            v: Some("test".to_string()),
+            p: Some(6881),
+            reqq: Some(250),
+            yourip: Some(vec![127, 0, 0, 1]),
+            metadata_size: Some(100),
+        };
+        let bytes = handshake.as_bytes();
+        assert_eq!(
+            bytes,
+            b"d1:md11:ut_metadatai1ee13:metadata_sizei100e1:pi6881e4:reqqi250e1:v4:test6:yourip4:\x7f\x00\x00\x01e"
+        );
+        let parsed = ExtHandshake::parse(&bytes).unwrap();
+        assert_eq!(parsed, handshake);
+        // unknown keys are ignored and everything is optional.
+        assert_eq!(
+            ExtHandshake::parse(b"d12:complete_agoi1ee"),
+            Some(ExtHandshake::default())
+        );
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = ExtRegistry::default();
+        registry.register("ut_metadata", 1);
+        registry.register("ut_pex", 2);
+        assert_eq!(registry.local_name(2), Some("ut_pex"));
+        assert_eq!(registry.local_name(1), Some("ut_metadata"));
+        assert_eq!(registry.local_name(9), None);
+        assert_eq!(registry.handshake().m.len(), 2);
+
+        assert_eq!(registry.remote_id("ut_metadata"), None);
+        registry.update(ExtHandshake {
+            m: [
+                ("ut_metadata".to_string(), 3),
+                ("lt_donthave".to_string(), 7),
+            ]
+            .into(),
+            ..ExtHandshake::default()
+        });
+        assert_eq!(registry.remote_id("ut_metadata"), Some(3));
+        assert_eq!(registry.remote_id("lt_donthave"), Some(7));
+        // a later handshake can turn one off and leaves the rest alone.
+        registry.update(ExtHandshake {
+            m: [("ut_metadata".to_string(), 0)].into(),
+            ..ExtHandshake::default()
+        });
+        assert_eq!(registry.remote_id("ut_metadata"), None);
+        assert_eq!(registry.remote_id("lt_donthave"), Some(7));
+    }
+}
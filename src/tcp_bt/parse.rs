@@ -1,7 +1,5 @@
 #![allow(dead_code)]
 
-use super::msg::structs::*;
-
 use crate::{
     field::{constant::*, ByteField},
     hash::Hasher,
@@ -10,7 +8,7 @@ use crate::{
 
 use std::{
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc, Mutex,
     },
     thread::JoinHandle,
@@ -23,7 +21,8 @@ use async_channel::{self, Receiver, Sender};
 
 pub struct ParseItem {
     pub rx: Receiver<Vec<u8>>,
-    pub tx: Sender<Request>,
+    // requests and extension messages, for the connection to answer.
+    pub tx: Sender<Message>,
     pub handle: task::JoinHandle<Option<()>>,
     pub field: Option<Arc<Mutex<ByteField>>>,
 }
@@ -31,6 +30,8 @@ pub struct Parser {
     pub tx: Sender<ParseItem>,
     pub rx: Receiver<ParseItem>,
     pub brk: AtomicBool,
+    // piece payload received from peers this session.
+    pub downloaded: AtomicU64,
 }
 
 impl Parser {
@@ -40,10 +41,17 @@ impl Parser {
             tx,
             rx,
             brk: AtomicBool::new(false),
+            downloaded: AtomicU64::new(0),
         }
     }
 }
 
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub fn spawn_parsers(
     parser: &Arc<Parser>,
     hasher: &Arc<Hasher>,
@@ -70,27 +78,17 @@ pub fn spawn_parsers(
 
                     let mut extbuf = vec![];
                     let mut pieces = vec![];
-                    'buf: loop {
-                        match handle.block_on(item.rx.recv()) {
-                            Ok(b) => unsafe {
-                                let x = extbuf.len();
-                                let y = b.len();
-                                extbuf.reserve(y);
-                                extbuf.set_len(x + y);
-                                let src = b.as_ptr();
-                                let d: *mut u8 = extbuf.as_mut_ptr();
-                                let dst = d.add(x);
-                                std::ptr::copy_nonoverlapping(src, dst, y);
-                            },
-                            Err(_) => break,
-                        }
+                    'buf: while let Ok(b) = handle.block_on(item.rx.recv()) {
+                        extbuf.extend_from_slice(&b);
 
                         let (_, parsed) = partial_parse(&mut extbuf);
                         for m in parsed {
                             match m {
                                 Message::Piece(piece) => {
-                                    if item.field.is_some() {
-                                        let field = item.field.as_ref().unwrap();
+                                    parser
+                                        .downloaded
+                                        .fetch_add(piece.data.len() as u64, Ordering::Relaxed);
+                                    if let Some(field) = item.field.as_ref() {
                                         {
                                             let mut f = field.lock().unwrap();
                                             f.arr[(piece.begin / SUBPIECE_LEN) as usize] = COMPLETE;
@@ -102,10 +100,12 @@ pub fn spawn_parsers(
                                         pieces.push(piece);
                                     }
                                 }
-                                Message::Request(req) => match handle.block_on(item.tx.send(req)) {
-                                    Ok(_) => {}
-                                    Err(_) => break,
-                                },
+                                m @ (Message::Request(_) | Message::Extended(_)) => {
+                                    match handle.block_on(item.tx.send(m)) {
+                                        Ok(_) => {}
+                                        Err(_) => break,
+                                    }
+                                }
                                 _ => continue,
                             }
                         }
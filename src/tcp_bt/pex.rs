@@ -0,0 +1,421 @@
+// BEP 11 ut_pex, trading the addresses of connected peers with each other.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncWriteExt,
+    net::tcp::OwnedWriteHalf,
+    sync::Mutex as TokioMutex,
+    task::{self, JoinHandle},
+    time,
+};
+
+use crate::bencode::{from_bytes, to_bytes};
+
+use super::{connect::Connector, extension::PeerExt, msg::structs::Extended};
+
+pub const UT_PEX: &str = "ut_pex";
+// the id we ask peers to send ut_pex messages with.
+pub const UT_PEX_ID: u8 = 2;
+// peers shouldn't send more than one message a minute.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+// slack for timer jitter when checking a peer's rate.
+const PEX_SLACK: Duration = Duration::from_secs(10);
+// at most this many added, and dropped, peers per message.
+pub const PEX_MAX_PEERS: usize = 50;
+// peers heard of but not connected to yet, the rest are dropped.
+const MAX_DISCOVERED: usize = 500;
+// how long a queued address is kept from being queued again, connected or not.
+const SEEN_TTL: Duration = Duration::from_secs(30 * 60);
+
+// added.f flag, we connected to them, so they accept incoming connections.
+pub const PEX_REACHABLE: u8 = 0x10;
+
+// compact peers, 6 bytes per ipv4 and 18 per ipv6 address, flags one byte each.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexMsg {
+    #[serde(default, with = "serde_bytes")]
+    pub added: Vec<u8>,
+    #[serde(rename = "added.f", default, with = "serde_bytes")]
+    pub added_f: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    pub dropped: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    pub added6: Vec<u8>,
+    #[serde(rename = "added6.f", default, with = "serde_bytes")]
+    pub added6_f: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    pub dropped6: Vec<u8>,
+}
+
+impl PexMsg {
+    pub fn new(added: &[(SocketAddr, u8)], dropped: &[SocketAddr]) -> Self {
+        let mut msg = PexMsg::default();
+        for (addr, flags) in added {
+            match addr.ip() {
+                IpAddr::V4(_) => {
+                    msg.added.extend(compact(addr));
+                    msg.added_f.push(*flags);
+                }
+                IpAddr::V6(_) => {
+                    msg.added6.extend(compact(addr));
+                    msg.added6_f.push(*flags);
+                }
+            }
+        }
+        for addr in dropped {
+            match addr.ip() {
+                IpAddr::V4(_) => msg.dropped.extend(compact(addr)),
+                IpAddr::V6(_) => msg.dropped6.extend(compact(addr)),
+            }
+        }
+        msg
+    }
+
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        from_bytes(payload).ok()
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        to_bytes(self).unwrap()
+    }
+
+    // added peers with their flags, missing flags count as none.
+    pub fn added(&self) -> Vec<(SocketAddr, u8)> {
+        let v4 = parse_compact(&self.added, 6)
+            .into_iter()
+            .enumerate()
+            .map(|(i, a)| (a, self.added_f.get(i).copied().unwrap_or(0)));
+        let v6 = parse_compact(&self.added6, 18)
+            .into_iter()
+            .enumerate()
+            .map(|(i, a)| (a, self.added6_f.get(i).copied().unwrap_or(0)));
+        v4.chain(v6).collect()
+    }
+
+    pub fn dropped(&self) -> Vec<SocketAddr> {
+        let mut ret = parse_compact(&self.dropped, 6);
+        ret.extend(parse_compact(&self.dropped6, 18));
+        ret
+    }
+}
+
+fn compact(addr: &SocketAddr) -> Vec<u8> {
+    let mut ret = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    ret.extend(addr.port().to_be_bytes());
+    ret
+}
+
+// width is 6 for ipv4 and 18 for ipv6, a trailing partial address is ignored.
+fn parse_compact(bytes: &[u8], width: usize) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(width)
+        .map(|c| {
+            let port = u16::from_be_bytes([c[width - 2], c[width - 1]]);
+            let ip = match width {
+                6 => IpAddr::from(<[u8; 4]>::try_from(&c[..4]).unwrap()),
+                _ => IpAddr::from(<[u8; 16]>::try_from(&c[..16]).unwrap()),
+            };
+            SocketAddr::new(ip, port)
+        })
+        .collect()
+}
+
+// peers we're connected to and peers we've heard of, shared by every connection.
+#[derive(Debug, Default)]
+pub struct Swarm {
+    connected: BTreeMap<SocketAddr, u8>,
+    // waiting for the main loop to connect to them.
+    discovered: Vec<SocketAddr>,
+    // addresses queued or connected to within SEEN_TTL and when, so pex can't
+    // make us redial the same peers over and over.
+    seen: HashMap<SocketAddr, Instant>,
+}
+
+impl Swarm {
+    pub fn connect(&mut self, addr: SocketAddr, flags: u8) {
+        self.connected.insert(addr, flags);
+        self.seen.insert(addr, Instant::now());
+    }
+
+    pub fn disconnect(&mut self, addr: &SocketAddr) {
+        self.connected.remove(addr);
+    }
+
+    pub fn connected(&self) -> &BTreeMap<SocketAddr, u8> {
+        &self.connected
+    }
+
+    pub fn discover(&mut self, addrs: impl IntoIterator<Item = SocketAddr>) {
+        self.discover_at(addrs, Instant::now());
+    }
+
+    fn discover_at(&mut self, addrs: impl IntoIterator<Item = SocketAddr>, now: Instant) {
+        self.seen.retain(|_, t| now.duration_since(*t) < SEEN_TTL);
+        for addr in addrs {
+            if self.discovered.len() >= MAX_DISCOVERED {
+                return;
+            }
+            if addr.port() == 0
+                || addr.ip().is_unspecified()
+                || self.connected.contains_key(&addr)
+                || self.seen.contains_key(&addr)
+            {
+                continue;
+            }
+            self.seen.insert(addr, now);
+            self.discovered.push(addr);
+        }
+    }
+
+    // unqueues peers another peer says have left, before we dial them for nothing.
+    pub fn forget(&mut self, addrs: impl IntoIterator<Item = SocketAddr>) {
+        let gone: BTreeSet<SocketAddr> = addrs.into_iter().collect();
+        self.discovered.retain(|a| !gone.contains(a));
+    }
+
+    pub fn discovered(&self) -> &[SocketAddr] {
+        &self.discovered
+    }
+
+    pub fn take_discovered(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.discovered)
+    }
+}
+
+// pex state of one connection.
+#[derive(Debug, Default)]
+pub struct PexPeer {
+    // the peer itself, never sent back to it.
+    pub addr: Option<SocketAddr>,
+    // what we've told the peer is connected.
+    sent: BTreeSet<SocketAddr>,
+    last_sent: Option<Instant>,
+    last_recv: Option<Instant>,
+}
+
+impl PexPeer {
+    pub fn new(addr: Option<SocketAddr>) -> Self {
+        Self {
+            addr,
+            ..Self::default()
+        }
+    }
+
+    // changes since the last message, None if there are none or it's too soon.
+    pub fn diff(&mut self, swarm: &Swarm, now: Instant) -> Option<PexMsg> {
+        if self
+            .last_sent
+            .is_some_and(|t| now.duration_since(t) < PEX_INTERVAL)
+        {
+            return None;
+        }
+        let connected = swarm.connected();
+        let added: Vec<(SocketAddr, u8)> = connected
+            .iter()
+            .filter(|(a, _)| !self.sent.contains(a) && Some(**a) != self.addr)
+            .take(PEX_MAX_PEERS)
+            .map(|(a, f)| (*a, *f))
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .iter()
+            .filter(|a| !connected.contains_key(a))
+            .take(PEX_MAX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        for (a, _) in &added {
+            self.sent.insert(*a);
+        }
+        for a in &dropped {
+            self.sent.remove(a);
+        }
+        self.last_sent = Some(now);
+        Some(PexMsg::new(&added, &dropped))
+    }
+
+    // whether a message from the peer arriving now respects the rate limit.
+    pub fn receive(&mut self, now: Instant) -> bool {
+        if self
+            .last_recv
+            .is_some_and(|t| now.duration_since(t) + PEX_SLACK < PEX_INTERVAL)
+        {
+            return false;
+        }
+        self.last_recv = Some(now);
+        true
+    }
+}
+
+// queues the peers a pex message added for the main loop to connect to and
+// unqueues the ones it dropped, messages that come too fast are ignored and
+// only the first peers of each are taken.
+pub fn handle_pex(connector: &Connector, pex: &mut PexPeer, payload: &[u8]) {
+    let msg = match PexMsg::parse(payload) {
+        Some(m) => m,
+        None => return,
+    };
+    if !pex.receive(Instant::now()) {
+        return;
+    }
+    let added = msg.added().into_iter().take(PEX_MAX_PEERS).map(|(a, _)| a);
+    let dropped = msg.dropped().into_iter().take(PEX_MAX_PEERS);
+    let mut swarm = connector.swarm.lock().unwrap();
+    swarm.discover(added);
+    swarm.forget(dropped);
+}
+
+// sends the peer our connected peers every PEX_INTERVAL, once it supports ut_pex.
+pub fn spawn_pex(
+    write: &Arc<TokioMutex<OwnedWriteHalf>>,
+    connector: &Arc<Connector>,
+    ext: &Arc<Mutex<PeerExt>>,
+) -> JoinHandle<()> {
+    let write = Arc::clone(write);
+    let connector = Arc::clone(connector);
+    let ext = Arc::clone(ext);
+    task::spawn(async move {
+        let mut interval = time::interval(PEX_INTERVAL);
+        // the first tick is immediate, wait a full interval before the first message.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if connector.brk.load(Ordering::Relaxed) {
+                return;
+            }
+            let msg = {
+                let mut ext = ext.lock().unwrap();
+                let id = match ext.registry.remote_id(UT_PEX) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                let swarm = connector.swarm.lock().unwrap();
+                ext.pex
+                    .diff(&swarm, Instant::now())
+                    .map(|m| Extended::new(id, m.as_bytes()))
+            };
+            if let Some(msg) = msg {
+                let w;
+                {
+                    let mut strm = write.lock().await;
+                    w = strm.write_all(&msg.as_bytes()).await;
+                }
+                if w.is_err() {
+                    return;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod pex_test {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_msg() {
+        let added = [
+            (addr("1.2.3.4:6881"), PEX_REACHABLE),
+            (addr("[::1]:6882"), 0),
+        ];
+        let msg = PexMsg::new(&added, &[addr("5.6.7.8:80")]);
+        assert_eq!(msg.added, [1, 2, 3, 4, 0x1a, 0xe1]);
+        assert_eq!(msg.added6.len(), 18);
+
+        let parsed = PexMsg::parse(&msg.as_bytes()).unwrap();
+        assert_eq!(parsed, msg);
+        assert_eq!(parsed.added(), added);
+        assert_eq!(parsed.dropped(), [addr("5.6.7.8:80")]);
+
+        // missing keys and flags are tolerated.
+        let parsed = PexMsg::parse(b"d5:added6:\x01\x02\x03\x04\x00\x50e").unwrap();
+        assert_eq!(parsed.added(), [(addr("1.2.3.4:80"), 0)]);
+        assert!(parsed.dropped().is_empty());
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut swarm = Swarm::default();
+        let me = addr("10.0.0.1:1");
+        swarm.connect(me, PEX_REACHABLE);
+        for i in 0..60 {
+            swarm.connect(addr(&format!("10.0.1.{}:6881", i)), PEX_REACHABLE);
+        }
+        let mut pex = PexPeer::new(Some(me));
+        let now = Instant::now();
+
+        // capped, and the peer never hears about itself.
+        let msg = pex.diff(&swarm, now).unwrap();
+        assert_eq!(msg.added().len(), PEX_MAX_PEERS);
+        assert!(msg.added().iter().all(|(a, _)| *a != me));
+
+        // at most once per interval.
+        assert_eq!(pex.diff(&swarm, now + Duration::from_secs(30)), None);
+
+        swarm.disconnect(&addr("10.0.1.0:6881"));
+        let msg = pex.diff(&swarm, now + PEX_INTERVAL).unwrap();
+        assert_eq!(msg.added().len(), 10);
+        assert_eq!(msg.dropped(), [addr("10.0.1.0:6881")]);
+
+        // nothing changed, nothing to send.
+        assert_eq!(pex.diff(&swarm, now + PEX_INTERVAL * 2), None);
+    }
+
+    #[test]
+    fn test_receive() {
+        let connector = Connector::new();
+        let mut pex = PexPeer::default();
+        let added: Vec<_> = (0..80)
+            .map(|i| (addr(&format!("10.0.2.{}:6881", i)), 0))
+            .collect();
+        let payload = PexMsg::new(&added, &[]).as_bytes();
+
+        handle_pex(&connector, &mut pex, &payload);
+        let found = connector.swarm.lock().unwrap().take_discovered();
+        assert_eq!(found.len(), PEX_MAX_PEERS);
+
+        // too soon, ignored.
+        let payload = PexMsg::new(&[(addr("10.0.3.1:6881"), 0)], &[]).as_bytes();
+        handle_pex(&connector, &mut pex, &payload);
+        assert!(connector.swarm.lock().unwrap().take_discovered().is_empty());
+
+        // dropped peers leave the queue.
+        let (a, b) = (addr("10.0.4.1:6881"), addr("10.0.4.2:6881"));
+        let mut pex = PexPeer::default();
+        handle_pex(
+            &connector,
+            &mut pex,
+            &PexMsg::new(&[(a, 0), (b, 0)], &[]).as_bytes(),
+        );
+        let mut pex = PexPeer::default();
+        handle_pex(&connector, &mut pex, &PexMsg::new(&[], &[a]).as_bytes());
+        assert_eq!(connector.swarm.lock().unwrap().take_discovered(), [b]);
+
+        // peers already queued once aren't queued again.
+        let mut swarm = Swarm::default();
+        swarm.discover(found.clone());
+        swarm.discover(found.clone());
+        assert_eq!(swarm.take_discovered().len(), PEX_MAX_PEERS);
+
+        // until they've gone unseen for SEEN_TTL.
+        swarm.discover_at(found, Instant::now() + SEEN_TTL);
+        assert_eq!(swarm.take_discovered().len(), PEX_MAX_PEERS);
+        assert_eq!(swarm.seen.len(), PEX_MAX_PEERS);
+    }
+}
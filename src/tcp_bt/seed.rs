@@ -27,7 +27,8 @@ use crate::{
 
 use super::{
     connect::{spawn_connecter_task, Connector},
-    msg::structs::Request,
+    extension::{handle_extended, PeerExt},
+    msg::{structs::Request, Message},
     parse::{ParseItem, Parser},
 };
 
@@ -49,7 +50,7 @@ pub async fn spawn_listener(
     let torrent = Arc::clone(torrent);
     let field = Arc::clone(field);
     let count = Arc::clone(count);
-    return task::spawn(async move {
+    task::spawn(async move {
         let mut handles = vec![];
         loop {
             match listener.accept().await {
@@ -57,6 +58,7 @@ pub async fn spawn_listener(
                     handles.push(
                         spawn_connecter_task(
                             Peer::Stream(socket),
+                            torrent.info_hash,
                             &parser,
                             &torrent,
                             &field,
@@ -79,22 +81,23 @@ pub async fn spawn_listener(
         for t in handles {
             t.await.unwrap();
         }
-    });
+    })
 }
 
 pub async fn fulfill_req(
     write: &Arc<TokioMutex<OwnedWriteHalf>>,
     torrent: &Arc<Client>,
     field: &Arc<Mutex<ByteField>>,
+    connector: &Arc<Connector>,
     count: &Arc<AtomicU32>,
     req: &Request,
 ) -> Option<()> {
     task::block_in_place(|| {
         let f = field.lock().unwrap();
         if f.arr[req.index as usize] != COMPLETE {
-            return None;
+            None
         } else {
-            return Some(());
+            Some(())
         }
     })?;
     let index = req.index as usize;
@@ -113,9 +116,13 @@ pub async fn fulfill_req(
     }
     w.ok()?;
     count.fetch_add(1, Ordering::Relaxed);
+    connector
+        .uploaded
+        .fetch_add(subp.data.len() as u64, Ordering::Relaxed);
     Some(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn torrent_seeder(
     read: &Arc<TokioMutex<OwnedReadHalf>>,
     write: &Arc<TokioMutex<OwnedWriteHalf>>,
@@ -123,6 +130,7 @@ pub async fn torrent_seeder(
     torrent: &Arc<Client>,
     field: &Arc<Mutex<ByteField>>,
     connector: &Arc<Connector>,
+    ext: &Arc<Mutex<PeerExt>>,
     count: &Arc<AtomicU32>,
 ) {
     let (byte_tx, byte_rx) = async_channel::unbounded();
@@ -135,6 +143,8 @@ pub async fn torrent_seeder(
     let field = Arc::clone(field);
     let connector = Arc::clone(connector);
     let count = Arc::clone(count);
+    let ext_connector = Arc::clone(&connector);
+    let ext_state = Arc::clone(ext);
 
     let reader = task::spawn(async move {
         let mut buf = [0u8; 65536];
@@ -167,17 +177,24 @@ pub async fn torrent_seeder(
     }
     let seeder = task::spawn(async move {
         loop {
-            let req = match req_rx.recv().await {
+            let msg = match req_rx.recv().await {
                 Ok(r) => r,
                 Err(_) => return,
             };
-            match fulfill_req(&write, &torrent, &field, &count, &req).await {
-                Some(_) => {}
-                None => return,
+            let done = match msg {
+                Message::Request(req) => {
+                    fulfill_req(&write, &torrent, &field, &ext_connector, &count, &req).await
+                }
+                Message::Extended(ext) => {
+                    handle_extended(&write, &torrent, &ext_connector, &ext_state, &ext).await
+                }
+                _ => Some(()),
+            };
+            if done.is_none() {
+                return;
             }
         }
     });
     // reader.await.unwrap();
     seeder.await.unwrap();
-    return;
 }
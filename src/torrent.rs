@@ -1,44 +1,112 @@
 use crate::{
-    bencode::{decode::parse, Item},
+    dht::DhtConfig,
     file::{parse_file, FileSize},
     hash::split_hashes,
-    tracker::get_info_hash,
+    magnet::Magnet,
+    metainfo::{Metainfo, MetainfoError, PieceV2},
 };
-use std::sync::Arc;
+use rand::{distributions::Alphanumeric, Rng};
+use std::{net::SocketAddr, sync::Arc};
 
 #[derive(Debug, Clone)]
 pub struct Client {
-    pub tree: Vec<Item>,
+    pub metainfo: Arc<Metainfo>,
     pub info_hash: [u8; 20],
+    // ours, sent in handshakes and announces.
+    pub peer_id: [u8; 20],
     pub piece_len: usize,
     pub num_pieces: usize,
     pub hashes: Vec<Vec<u8>>,
+    // v2 merkle hashes, checked alongside `hashes` for hybrid torrents.
+    pub hashes_v2: Vec<PieceV2>,
     pub files: Arc<Vec<FileSize>>,
     pub file_len: usize,
+    // peers to try before any tracker answers, from magnet links.
+    pub peers: Vec<SocketAddr>,
+    // magnet peers given by hostname, resolved when the client starts.
+    pub peer_hosts: Vec<String>,
+    // None keeps the client off the DHT, always so for private torrents.
+    pub dht: Option<DhtConfig>,
+    // local service discovery, off for private torrents.
+    pub lsd: bool,
 }
 
 impl Client {
-    pub async fn new(bytes: &[u8]) -> Self {
-        let mut copy = bytes.to_vec();
-        let tree = parse(&mut copy);
-        let dict = tree[0].get_dict();
-        let info = dict.get("info".as_bytes()).unwrap().get_dict();
-        // piece length: https://wiki.theory.org/BitTorrentSpecification#Metadata_Piece_Length
-        let piece_len = info.get("piece length".as_bytes()).unwrap().get_integer();
-        let num_pieces = info.get("pieces".as_bytes()).unwrap().get_string().len() / 20;
-        let hashes = info.get("pieces".as_bytes()).unwrap().get_string();
-        let split_hashes = split_hashes(&hashes);
-        let (files, file_len) = parse_file(&info).await;
+    pub async fn new(bytes: &[u8]) -> Result<Self, MetainfoError> {
+        Self::from_metainfo(Metainfo::from_bytes(bytes)?, vec![]).await
+    }
 
+    // a client still waiting on its info dict, nothing is created on disk
+    // until with_info is called.
+    pub fn from_magnet(magnet: &Magnet) -> Self {
         Self {
-            tree,
+            metainfo: Arc::new(Metainfo::from_magnet(magnet)),
+            info_hash: magnet.info_hash,
+            peer_id: make_peer_id(),
+            piece_len: 0,
+            num_pieces: 0,
+            hashes: vec![],
+            hashes_v2: vec![],
+            files: Arc::new(vec![]),
+            file_len: 0,
+            peers: magnet.peers.clone(),
+            peer_hosts: magnet.peer_hosts.clone(),
+            dht: Some(DhtConfig::default()),
+            lsd: true,
+        }
+    }
+
+    // leaves the metadata pending state once a peer sends the info dict.
+    pub async fn with_info(&self, info: &[u8]) -> Result<Self, MetainfoError> {
+        let metainfo = self.metainfo.with_info(info)?;
+        let client = Self::from_metainfo(metainfo, self.peers.clone()).await?;
+        Ok(Self {
+            peer_id: self.peer_id,
+            ..client
+        })
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.metainfo.is_pending()
+    }
+
+    async fn from_metainfo(
+        metainfo: Metainfo,
+        peers: Vec<SocketAddr>,
+    ) -> Result<Self, MetainfoError> {
+        metainfo.validate()?;
+        let (files, file_len) = parse_file(&metainfo).await;
+
+        Ok(Self {
             // 20bits of sha1 hash->info value from meta file.
-            info_hash: get_info_hash(bytes.to_vec()),
-            piece_len,
-            num_pieces,
-            hashes: split_hashes,
+            info_hash: metainfo.info_hash(),
+            peer_id: make_peer_id(),
+            // piece length: https://wiki.theory.org/BitTorrentSpecification#Metadata_Piece_Length
+            piece_len: metainfo.piece_length(),
+            num_pieces: metainfo.num_pieces(),
+            hashes: split_hashes(metainfo.pieces()),
+            hashes_v2: metainfo.pieces_v2(),
             files,
             file_len,
-        }
+            peers,
+            peer_hosts: vec![],
+            // BEP 27, private torrents only get peers from their trackers.
+            dht: match metainfo.private() {
+                true => None,
+                false => Some(DhtConfig::default()),
+            },
+            lsd: !metainfo.private(),
+            metainfo: Arc::new(metainfo),
+        })
+    }
+}
+
+// Azureus style, client and version then random characters.
+fn make_peer_id() -> [u8; 20] {
+    let mut id = *b"-RS0100-000000000000";
+    let mut rng = rand::thread_rng();
+    for b in &mut id[8..] {
+        *b = rng.sample(Alphanumeric);
     }
+    id
 }
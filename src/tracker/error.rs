@@ -0,0 +1,42 @@
+use std::{error::Error, fmt::Display, io};
+
+#[derive(Debug)]
+pub enum TrackerError {
+    Io(io::Error),
+    // the tracker took too long to answer.
+    Timeout,
+    // a url we can't use, or whose host didn't resolve.
+    Url(String),
+    // a malformed http response.
+    Http(String),
+    // an http status other than success or a redirect.
+    Status(u16),
+    TooManyRedirects,
+    // a response that isn't a valid tracker response.
+    Decode(String),
+    // the tracker's own failure reason.
+    Failure(String),
+}
+
+impl Display for TrackerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackerError::Io(e) => write!(f, "tracker io error: {}", e),
+            TrackerError::Timeout => write!(f, "tracker timed out"),
+            TrackerError::Url(e) => write!(f, "bad tracker url: {}", e),
+            TrackerError::Http(e) => write!(f, "bad http response: {}", e),
+            TrackerError::Status(s) => write!(f, "tracker answered with http status {}", s),
+            TrackerError::TooManyRedirects => write!(f, "too many tracker redirects"),
+            TrackerError::Decode(e) => write!(f, "invalid tracker response: {}", e),
+            TrackerError::Failure(e) => write!(f, "tracker failure: {}", e),
+        }
+    }
+}
+
+impl Error for TrackerError {}
+
+impl From<io::Error> for TrackerError {
+    fn from(e: io::Error) -> Self {
+        TrackerError::Io(e)
+    }
+}
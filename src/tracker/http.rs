@@ -1,85 +1,672 @@
 // http tracker functionality.
 #![allow(dead_code)]
 
-use std::{io::Error, io::ErrorKind, net::SocketAddr, str::from_utf8};
+use flate2::read::GzDecoder;
+use serde::{de::IgnoredAny, Deserialize};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    io::Read,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
+    time::timeout,
 };
 
-use crate::bencode::{decode::parse, Item};
+use crate::bencode::from_bytes;
 
-use super::IpPort;
+use super::{resolve, AnnounceRequest, AnnounceResponse, IpPort, ScrapeStats, TrackerError};
 
-// takes in info_hash and tracker addr, announce and gets peer IpPorts.
-pub async fn http_announce(
-    addr: SocketAddr,
-    info_hash: [u8; 20],
+// per request, redirects get a fresh one.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_REDIRECTS: usize = 5;
+const MAX_HEADER: usize = 16 * 1024;
+const MAX_BODY: usize = 8 << 20;
+// info hashes per scrape request, keeps the url a sane length.
+const SCRAPE_BATCH: usize = 50;
+
+#[derive(Debug, Deserialize)]
+struct AnnounceResp {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    interval: Option<u32>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<u32>,
+    // opaque, trackers may put anything in it.
+    #[serde(rename = "tracker id", default, with = "serde_bytes")]
+    tracker_id: Option<Vec<u8>>,
+    complete: Option<u32>,
+    incomplete: Option<u32>,
+    // absent when the announce failed.
+    peers: Option<Peers>,
+}
+
+// BEP 23 compact peers, or the BEP 3 list of dicts trackers fall back to.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Peers {
+    Compact(ByteBuf),
+    Dicts(Vec<PeerDict>),
+    // anything else gets no peers rather than failing the announce.
+    Unknown(IgnoredAny),
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerDict {
+    ip: String,
     port: u16,
-) -> Result<Vec<IpPort>, Error> {
-    let mut get: Vec<u8> = vec![];
-    // prefix
-    let mut base: String = "GET /announce?info_hash=".to_string();
-    // appending each hex as a string
-    for byte in &info_hash {
-        base.push_str(&format!("{:02x}", byte));
-    }
-    // append suffix of get request
-    base.push_str("&peer_id=-qB4250-rj6kZQu4P_Mh&port=");
-    base.push_str(&format!("{}", port));
-    base.push_str("&uploaded=0&downloaded=0&left=1456927919\
-        &corrupt=0&ket=8B26698B&event=started&numwant=200&compact=1&no_peer_id=1&supportcrypto=1&edundant=0\
-        HTTP/1.1\r\n\r\n");
-    // convert base to Vec<u8> and append to get vector
-    get.extend_from_slice(&base.as_bytes());
-    // connect to the tracker
-    let mut stream = TcpStream::connect(addr).await?;
-    // send the request to the tracker
-    stream.write_all(&get).await?;
-    // read it's reply
-    let mut buf: Vec<_> = vec![0; 10000];
-    let len;
-    match stream.read(&mut buf).await {
-        Ok(l) => len = l,
-        Err(e) => return Err(Error::new(ErrorKind::Other, e.to_string())),
-    }
-    buf.truncate(len);
-    // remove http header
-    let mut count = 0;
-    for i in buf.windows(4) {
-        count += 1;
-        if i.iter().zip(b"\r\n\r\n").all(|(a, b)| *a == *b) {
-            break;
+}
+
+impl Peers {
+    // dicts naming a host rather than an ip are skipped, resolving them would block.
+    fn addrs(&self) -> Vec<SocketAddr> {
+        match self {
+            Peers::Compact(bytes) => IpPort::from_bytes(bytes).iter().map(IpPort::addr).collect(),
+            Peers::Dicts(dicts) => dicts
+                .iter()
+                .filter_map(|d| Some(SocketAddr::new(d.ip.parse::<IpAddr>().ok()?, d.port)))
+                .collect(),
+            Peers::Unknown(_) => vec![],
+        }
+    }
+}
+
+pub fn parse_announce(body: &[u8]) -> Result<AnnounceResponse, TrackerError> {
+    let resp: AnnounceResp = from_bytes(body).map_err(|e| TrackerError::Decode(e.to_string()))?;
+    if let Some(e) = resp.failure_reason {
+        return Err(TrackerError::Failure(e));
+    }
+    Ok(AnnounceResponse {
+        interval: resp.interval,
+        min_interval: resp.min_interval,
+        tracker_id: resp.tracker_id,
+        complete: resp.complete,
+        incomplete: resp.incomplete,
+        warning: resp.warning_message,
+        peers: resp.peers.map(|p| p.addrs()).unwrap_or_default(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeResp {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(default)]
+    files: BTreeMap<ByteBuf, ScrapeFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeFile {
+    #[serde(default)]
+    complete: u32,
+    #[serde(default)]
+    downloaded: u32,
+    #[serde(default)]
+    incomplete: u32,
+}
+
+// stats per info hash, torrents the tracker doesn't know are left out.
+pub fn parse_scrape(body: &[u8]) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    let resp: ScrapeResp = from_bytes(body).map_err(|e| TrackerError::Decode(e.to_string()))?;
+    if let Some(e) = resp.failure_reason {
+        return Err(TrackerError::Failure(e));
+    }
+    Ok(resp
+        .files
+        .into_iter()
+        .filter_map(|(hash, f)| {
+            let hash = <[u8; 20]>::try_from(hash.as_slice()).ok()?;
+            let stats = ScrapeStats {
+                seeders: f.complete,
+                completed: f.downloaded,
+                leechers: f.incomplete,
+            };
+            Some((hash, stats))
+        })
+        .collect())
+}
+
+// an http tracker url, path keeps any query string the tracker gave us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl TrackerUrl {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let rest = match url.strip_prefix("http://") {
+            Some(r) => r,
+            None => return Err(format!("not an http url: {}", url)),
+        };
+        // fragments never go to the server.
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest.as_bytes()[i] == b'?' => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+        // [v6]:port, host:port or just host.
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((h, p)) if !p.contains(']') => {
+                let port = p
+                    .parse()
+                    .map_err(|_| format!("invalid port in url: {}", url))?;
+                (h, port)
+            }
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("no host in url: {}", url));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+
+    // the Host header, the port is left out when it's the default.
+    pub fn host_header(&self) -> String {
+        match self.port {
+            80 => self.host.clone(),
+            p => format!("{}:{}", self.host, p),
+        }
+    }
+
+    // the scrape convention, the last path segment's "announce" becomes "scrape".
+    // None when the tracker doesn't follow it and so can't be scraped.
+    pub fn scrape_url(&self) -> Option<Self> {
+        let (path, query) = match self.path.split_once('?') {
+            Some((p, q)) => (p, Some(q)),
+            None => (self.path.as_str(), None),
+        };
+        let (dir, name) = path.rsplit_once('/')?;
+        let rest = name.strip_prefix("announce")?;
+        let mut path = format!("{}/scrape{}", dir, rest);
+        if let Some(q) = query {
+            write!(path, "?{}", q).unwrap();
         }
+        Some(Self {
+            path,
+            ..self.clone()
+        })
+    }
+
+    // path with params appended to whatever query string is already there.
+    pub fn with_query(&self, params: &str) -> String {
+        let sep = match self.path.contains('?') {
+            true if self.path.ends_with(['?', '&']) => "",
+            true => "&",
+            false => "?",
+        };
+        format!("{}{}{}", self.path, sep, params)
+    }
+}
+
+// RFC 3986 unreserved characters are kept, every other byte becomes %XX.
+pub fn percent_encode(bytes: &[u8]) -> String {
+    let mut ret = String::with_capacity(bytes.len() * 3);
+    for b in bytes {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                ret.push(*b as char)
+            }
+            _ => write!(ret, "%{:02X}", b).unwrap(),
+        }
+    }
+    ret
+}
+
+// announce parameters, https://www.bittorrent.org/beps/bep_0003.html#trackers
+pub fn announce_query(req: &AnnounceRequest) -> String {
+    let mut query = format!(
+        "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1&numwant={}",
+        percent_encode(&req.info_hash),
+        percent_encode(&req.peer_id),
+        req.port,
+        req.uploaded,
+        req.downloaded,
+        req.left,
+        req.numwant,
+    );
+    if let Some(event) = req.event.as_str() {
+        write!(query, "&event={}", event).unwrap();
+    }
+    if let Some(key) = req.key {
+        write!(query, "&key={:08X}", key).unwrap();
     }
-    buf.drain(0..count + 4);
-    if buf[0] != b'd' {
-        buf.insert(0, b'd');
-        buf.push(b'e');
-    }
-    // parse out ip port and return
-    let tree: Vec<Item> = parse(&mut buf);
-    match &tree[0] {
-        Item::Dict(d) => {
-            if let Some(e) = d.get("failure reason".as_bytes()) {
-                match e {
-                    Item::String(s) => {
-                        return Err(Error::new(
-                            ErrorKind::Other,
-                            from_utf8(s).unwrap().to_string(),
-                        ))
-                    }
-                    _ => unreachable!(),
+    if let Some(id) = &req.tracker_id {
+        write!(query, "&trackerid={}", percent_encode(id)).unwrap();
+    }
+    query
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    // header names are case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// reads more of the response into buf, running out early is an error.
+async fn read_more<R: AsyncRead + Unpin>(r: &mut R, buf: &mut Vec<u8>) -> Result<(), TrackerError> {
+    let mut chunk = [0u8; 4096];
+    let n = r.read(&mut chunk).await?;
+    if n == 0 {
+        return Err(TrackerError::Http(
+            "connection closed mid response".to_string(),
+        ));
+    }
+    if buf.len() + n > MAX_BODY {
+        return Err(TrackerError::Http("response too large".to_string()));
+    }
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(())
+}
+
+async fn fill<R: AsyncRead + Unpin>(
+    r: &mut R,
+    buf: &mut Vec<u8>,
+    len: usize,
+) -> Result<(), TrackerError> {
+    while buf.len() < len {
+        read_more(r, buf).await?;
+    }
+    Ok(())
+}
+
+// a whole response, the body delimited by Content-Length, chunked encoding or
+// the connection closing, and gunzipped if need be.
+pub async fn read_response<R: AsyncRead + Unpin>(r: &mut R) -> Result<HttpResponse, TrackerError> {
+    let mut buf = vec![];
+    let head_end = loop {
+        if let Some(i) = find(&buf, b"\r\n\r\n") {
+            break i;
+        }
+        if buf.len() > MAX_HEADER {
+            return Err(TrackerError::Http("headers too large".to_string()));
+        }
+        read_more(r, &mut buf).await?;
+    };
+    let head = std::str::from_utf8(&buf[..head_end])
+        .map_err(|_| TrackerError::Http("headers aren't utf-8".to_string()))?;
+    let mut lines = head.split("\r\n");
+    // HTTP/1.1 200 OK
+    let status_line = lines.next().unwrap_or_default();
+    let status = match status_line.split(' ').collect::<Vec<_>>()[..] {
+        [version, code, ..] if version.starts_with("HTTP/1.") => code.parse().ok(),
+        _ => None,
+    };
+    let status =
+        status.ok_or_else(|| TrackerError::Http(format!("bad status line: {}", status_line)))?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+    let mut resp = HttpResponse {
+        status,
+        headers,
+        body: vec![],
+    };
+    let mut rest = buf.split_off(head_end + 4);
+
+    let chunked = resp
+        .header("Transfer-Encoding")
+        .is_some_and(|t| t.to_ascii_lowercase().contains("chunked"));
+    if chunked {
+        let mut pos = 0;
+        loop {
+            let line_end = loop {
+                if let Some(i) = find(&rest[pos..], b"\r\n") {
+                    break pos + i;
                 }
+                read_more(r, &mut rest).await?;
+            };
+            let size = std::str::from_utf8(&rest[pos..line_end])
+                .ok()
+                .and_then(|l| l.split(';').next())
+                .and_then(|l| usize::from_str_radix(l.trim(), 16).ok())
+                .ok_or_else(|| TrackerError::Http("bad chunk size".to_string()))?;
+            pos = line_end + 2;
+            // trailers after the last chunk are ignored.
+            if size == 0 {
+                break;
             }
+            if resp.body.len() + size > MAX_BODY {
+                return Err(TrackerError::Http("response too large".to_string()));
+            }
+            fill(r, &mut rest, pos + size + 2).await?;
+            resp.body.extend_from_slice(&rest[pos..pos + size]);
+            pos += size + 2;
+        }
+    } else if let Some(len) = resp.header("Content-Length") {
+        let len: usize = len
+            .parse()
+            .map_err(|_| TrackerError::Http(format!("bad content length: {}", len)))?;
+        if len > MAX_BODY {
+            return Err(TrackerError::Http("response too large".to_string()));
         }
-        _ => unreachable!(),
-    }
-    let peers = tree[0]
-        .get_dict()
-        .get("peers".as_bytes())
-        .unwrap()
-        .get_string();
-    Ok(IpPort::from_bytes(&peers))
+        fill(r, &mut rest, len).await?;
+        rest.truncate(len);
+        resp.body = rest;
+    } else {
+        r.take(MAX_BODY as u64).read_to_end(&mut rest).await?;
+        resp.body = rest;
+    }
+
+    match resp
+        .header("Content-Encoding")
+        .map(|e| e.to_ascii_lowercase())
+    {
+        None => {}
+        Some(e) if e == "identity" => {}
+        Some(e) if e == "gzip" || e == "x-gzip" => {
+            let mut body = vec![];
+            GzDecoder::new(&resp.body[..])
+                .take(MAX_BODY as u64)
+                .read_to_end(&mut body)
+                .map_err(|e| TrackerError::Http(format!("bad gzip body: {}", e)))?;
+            resp.body = body;
+        }
+        Some(e) => return Err(TrackerError::Http(format!("unsupported encoding {}", e))),
+    }
+    Ok(resp)
+}
+
+pub fn get_request(url: &TrackerUrl) -> Vec<u8> {
+    format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}/{}\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n",
+        url.path,
+        url.host_header(),
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+    )
+    .into_bytes()
+}
+
+// GETs url.path from the server at addr, following redirects, the body of
+// the final 2xx response is returned.
+pub async fn http_get(addr: SocketAddr, url: &TrackerUrl) -> Result<Vec<u8>, TrackerError> {
+    let (mut addr, mut url) = (addr, url.clone());
+    for _ in 0..=MAX_REDIRECTS {
+        let resp = timeout(HTTP_TIMEOUT, async {
+            let mut stream = TcpStream::connect(addr).await?;
+            stream.write_all(&get_request(&url)).await?;
+            read_response(&mut stream).await
+        })
+        .await
+        .map_err(|_| TrackerError::Timeout)??;
+        match resp.status {
+            200..=299 => return Ok(resp.body),
+            301 | 302 | 303 | 307 | 308 => {
+                let location = resp
+                    .header("Location")
+                    .ok_or_else(|| TrackerError::Http("redirect without location".to_string()))?;
+                // absolute urls may move us to another host, relative ones keep it.
+                if location.starts_with('/') {
+                    url.path = location.to_string();
+                } else {
+                    url = TrackerUrl::parse(location).map_err(TrackerError::Url)?;
+                    addr = resolve(&url.host, url.port)
+                        .await
+                        .map_err(TrackerError::Url)?;
+                }
+            }
+            s => return Err(TrackerError::Status(s)),
+        }
+    }
+    Err(TrackerError::TooManyRedirects)
+}
+
+// announces to the tracker at addr and gets peer IpPorts.
+pub async fn http_announce(
+    addr: SocketAddr,
+    url: &TrackerUrl,
+    req: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
+    let target = TrackerUrl {
+        path: url.with_query(&announce_query(req)),
+        ..url.clone()
+    };
+    parse_announce(&http_get(addr, &target).await?)
+}
+
+// scrapes the tracker at addr, batching the info hashes over several requests.
+pub async fn http_scrape(
+    addr: SocketAddr,
+    url: &TrackerUrl,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    let url = url
+        .scrape_url()
+        .ok_or_else(|| TrackerError::Url("tracker doesn't support scrape".to_string()))?;
+    let mut stats = HashMap::new();
+    for batch in info_hashes.chunks(SCRAPE_BATCH) {
+        let query: Vec<String> = batch
+            .iter()
+            .map(|h| format!("info_hash={}", percent_encode(h)))
+            .collect();
+        let target = TrackerUrl {
+            path: url.with_query(&query.join("&")),
+            ..url.clone()
+        };
+        stats.extend(parse_scrape(&http_get(addr, &target).await?)?);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod http_test {
+    use super::super::Event;
+    use super::*;
+
+    #[test]
+    fn test_url() {
+        let url = TrackerUrl::parse("http://tracker.example:6969/announce?passkey=abc#x").unwrap();
+        assert_eq!(url.host, "tracker.example");
+        assert_eq!(url.port, 6969);
+        assert_eq!(url.path, "/announce?passkey=abc");
+        assert_eq!(url.host_header(), "tracker.example:6969");
+        assert_eq!(url.with_query("a=1"), "/announce?passkey=abc&a=1");
+
+        let url = TrackerUrl::parse("http://[::1]?x=1").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("[::1]", 80));
+        assert_eq!(url.with_query("a=1"), "/?x=1&a=1");
+        assert_eq!(
+            TrackerUrl::parse("http://t/a").unwrap().with_query("a=1"),
+            "/a?a=1"
+        );
+
+        assert!(TrackerUrl::parse("udp://t:80").is_err());
+        assert!(TrackerUrl::parse("http://t:port/").is_err());
+    }
+
+    #[test]
+    fn test_scrape() {
+        let scrape = |u: &str| TrackerUrl::parse(u).unwrap().scrape_url().map(|u| u.path);
+        assert_eq!(scrape("http://t/announce").as_deref(), Some("/scrape"));
+        assert_eq!(
+            scrape("http://t/x/announce.php?passkey=a").as_deref(),
+            Some("/x/scrape.php?passkey=a")
+        );
+        assert_eq!(scrape("http://t/a"), None);
+        assert_eq!(scrape("http://t/announce/x"), None);
+
+        let body = b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e\
+                     10:incompletei10e4:name1:xe3:badd8:completei1eeee";
+        let stats = parse_scrape(body).unwrap();
+        assert_eq!(stats.len(), 1);
+        let want = ScrapeStats {
+            seeders: 5,
+            completed: 50,
+            leechers: 10,
+        };
+        assert_eq!(stats[b"aaaaaaaaaaaaaaaaaaaa"], want);
+        assert!(matches!(
+            parse_scrape(b"d14:failure reason4:nopee"),
+            Err(TrackerError::Failure(_))
+        ));
+    }
+
+    #[test]
+    fn test_request() {
+        assert_eq!(percent_encode(b"\x12\x34Az.-_~ /"), "%124Az.-_~%20%2F");
+
+        let mut req = AnnounceRequest::new([0xab; 20], *b"-RS0100-abcdefghijkl", 6881);
+        req.uploaded = 1;
+        req.downloaded = 2;
+        req.left = 3;
+        req.event = Event::Started;
+        let url = TrackerUrl::parse("http://t:8080/ann?k=v").unwrap();
+        let url = TrackerUrl {
+            path: url.with_query(&announce_query(&req)),
+            ..url
+        };
+        let get = String::from_utf8(get_request(&url)).unwrap();
+        let line = format!(
+            "GET /ann?k=v&info_hash={}&peer_id=-RS0100-abcdefghijkl&port=6881\
+             &uploaded=1&downloaded=2&left=3&compact=1&numwant=200&event=started HTTP/1.1\r\n",
+            "%AB".repeat(20)
+        );
+        assert!(get.starts_with(&line));
+        assert!(get.contains("\r\nHost: t:8080\r\n"));
+        assert!(get.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_announce_response() {
+        let body = b"d8:completei5e10:incompletei3e8:intervali1800e12:min intervali60e\
+                     5:peers6:\x7f\x00\x00\x01\x1a\xe110:tracker id2:id\
+                     15:warning message4:oopse";
+        let resp = parse_announce(body).unwrap();
+        assert_eq!(
+            resp,
+            AnnounceResponse {
+                interval: Some(1800),
+                min_interval: Some(60),
+                tracker_id: Some(b"id".to_vec()),
+                complete: Some(5),
+                incomplete: Some(3),
+                warning: Some("oops".to_string()),
+                peers: vec!["127.0.0.1:6881".parse().unwrap()],
+            }
+        );
+
+        let body = b"d8:intervali60e5:peersld2:ip9:127.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa\
+                     4:porti6881eed2:ip7:tracker4:porti1eed2:ip3:::14:porti6882eeee";
+        assert_eq!(
+            parse_announce(body).unwrap().peers,
+            [
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:6882".parse().unwrap()
+            ]
+        );
+        // a form we don't know gives no peers, not an error.
+        let resp = parse_announce(b"d8:intervali60e5:peersi3ee").unwrap();
+        assert_eq!((resp.interval, resp.peers.len()), (Some(60), 0));
+
+        assert!(matches!(
+            parse_announce(b"d14:failure reason6:bannede"),
+            Err(TrackerError::Failure(r)) if r == "banned"
+        ));
+
+        let mut req = AnnounceRequest::new([0; 20], [0; 20], 1);
+        req.tracker_id = Some(b"a b\xff".to_vec());
+        assert!(announce_query(&req).ends_with("&trackerid=a%20b%FF"));
+    }
+
+    fn read(bytes: &[u8]) -> Result<HttpResponse, TrackerError> {
+        tokio_test::block_on(read_response(&mut &bytes[..]))
+    }
+
+    #[test]
+    fn test_response() {
+        let resp = read(b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nd1:ae trailing").unwrap();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.header("Content-Length"), Some("5"));
+        assert_eq!(resp.body, b"d1:ae");
+
+        let resp = read(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3;ext=1\r\nd1:\r\n2\r\nae\r\n0\r\nTrailer: x\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(resp.body, b"d1:ae");
+
+        // no length, the body runs until the connection closes.
+        let resp = read(b"HTTP/1.0 302 Found\r\nLocation: /x\r\n\r\nmoved").unwrap();
+        assert_eq!((resp.status, resp.body.as_slice()), (302, &b"moved"[..]));
+
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        std::io::Write::write_all(&mut gz, b"d1:ae").unwrap();
+        let gz = gz.finish().unwrap();
+        let mut bytes = format!(
+            "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+            gz.len()
+        )
+        .into_bytes();
+        bytes.extend(gz);
+        assert_eq!(read(&bytes).unwrap().body, b"d1:ae");
+
+        assert!(matches!(
+            read(b"SSH-2.0\r\n\r\n"),
+            Err(TrackerError::Http(_))
+        ));
+        assert!(matches!(
+            read(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort"),
+            Err(TrackerError::Http(_))
+        ));
+    }
+
+    #[test]
+    fn test_get() {
+        tokio_test::block_on(async {
+            use tokio::net::TcpListener;
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                // a redirect, then the answer.
+                let replies: [&[u8]; 2] = [
+                    b"HTTP/1.1 301 Moved\r\nLocation: /new?a=1\r\nContent-Length: 0\r\n\r\n",
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+                ];
+                let mut paths = vec![];
+                for reply in replies {
+                    let (mut s, _) = listener.accept().await.unwrap();
+                    let mut buf = vec![0; 1024];
+                    let n = s.read(&mut buf).await.unwrap();
+                    let req = String::from_utf8_lossy(&buf[..n]).to_string();
+                    paths.push(req.split(' ').nth(1).unwrap().to_string());
+                    s.write_all(reply).await.unwrap();
+                }
+                paths
+            });
+            let url = TrackerUrl::parse(&format!("http://{}/old", addr)).unwrap();
+            assert_eq!(http_get(addr, &url).await.unwrap(), b"ok");
+            assert_eq!(server.await.unwrap(), ["/old", "/new?a=1"]);
+        });
+    }
 }
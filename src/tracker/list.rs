@@ -0,0 +1,187 @@
+// BEP 12 multitracker metadata, tiers of trackers tried in order.
+
+use std::{collections::HashMap, time::Duration};
+
+use rand::seq::SliceRandom;
+use tokio::time::timeout;
+
+use crate::metainfo::Metainfo;
+
+use super::{
+    announce, make_addr, scrape, udp::Backoff, AnnounceRequest, AnnounceResponse, ScrapeStats,
+    TrackerError,
+};
+
+// a backstop per tracker, a minute past a udp request's BEP 15 retries, which
+// outlast http's redirects.
+pub const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(Backoff::SPEC.total().as_secs() + 60);
+
+#[derive(Debug, Clone, Default)]
+pub struct TrackerList {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerList {
+    // announce-list when there is one, else the lone announce url.
+    pub fn new(meta: &Metainfo) -> Self {
+        let tiers = match (meta.announce_list(), meta.announce()) {
+            (list, _) if list.iter().any(|t| !t.is_empty()) => list.to_vec(),
+            (_, Some(url)) => vec![vec![url.to_string()]],
+            (_, None) => vec![],
+        };
+        Self::from_tiers(tiers)
+    }
+
+    // trackers within a tier are shuffled once, empty tiers dropped.
+    pub fn from_tiers(tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+        let tiers = tiers
+            .into_iter()
+            .filter(|t| !t.is_empty())
+            .map(|mut t| {
+                t.shuffle(&mut rng);
+                t
+            })
+            .collect();
+        Self { tiers }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    // moves a tracker that answered to the front of its tier.
+    pub fn promote(&mut self, tier: usize, index: usize) {
+        let url = self.tiers[tier].remove(index);
+        self.tiers[tier].insert(0, url);
+    }
+
+    // promote by url, for answers to announces sent before the tier last changed.
+    pub fn promote_url(&mut self, tier: usize, url: &str) {
+        if let Some(i) = self.tiers[tier].iter().position(|u| u == url) {
+            self.promote(tier, i);
+        }
+    }
+
+    // scrapes the first tracker in the tier that answers, without reordering it.
+    pub async fn scrape_tier(
+        &self,
+        tier: usize,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+        let mut err = TrackerError::Url("empty tier".to_string());
+        for url in &self.tiers[tier] {
+            let addr = match make_addr(url).await {
+                Ok(a) => a,
+                Err(e) => {
+                    err = TrackerError::Url(e);
+                    continue;
+                }
+            };
+            match timeout(ANNOUNCE_TIMEOUT, scrape(&addr, info_hashes)).await {
+                Ok(Ok(stats)) => return Ok(stats),
+                Ok(Err(e)) => err = e,
+                Err(_) => err = TrackerError::Timeout,
+            }
+        }
+        Err(err)
+    }
+}
+
+// tries a tier's urls in order until one answers, with the index of the one that
+// did. reordering is left to the caller, see promote_url, so it can run apart
+// from the list.
+pub async fn announce_urls(
+    urls: &[String],
+    req: &AnnounceRequest,
+) -> Result<(AnnounceResponse, usize), TrackerError> {
+    let mut err = TrackerError::Url("empty tier".to_string());
+    for (i, url) in urls.iter().enumerate() {
+        let addr = match make_addr(url).await {
+            Ok(a) => a,
+            Err(e) => {
+                err = TrackerError::Url(e);
+                continue;
+            }
+        };
+        match timeout(ANNOUNCE_TIMEOUT, announce(&addr, req)).await {
+            Ok(Ok(resp)) => return Ok((resp, i)),
+            Ok(Err(e)) => err = e,
+            Err(_) => err = TrackerError::Timeout,
+        }
+    }
+    Err(err)
+}
+
+#[cfg(test)]
+mod list_test {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn test_tiers() {
+        let tiers = vec![
+            vec!["udp://a:1".to_string()],
+            vec![],
+            vec!["udp://b:1".to_string(), "udp://c:1".to_string()],
+        ];
+        let mut list = TrackerList::from_tiers(tiers);
+        assert_eq!(list.tiers().len(), 2);
+        let mut second = list.tiers()[1].clone();
+        second.sort();
+        assert_eq!(second, ["udp://b:1", "udp://c:1"]);
+
+        let last = list.tiers()[1][1].clone();
+        list.promote(1, 1);
+        assert_eq!(list.tiers()[1][0], last);
+        let other = list.tiers()[1][1].clone();
+        list.promote_url(1, &other);
+        list.promote_url(1, "udp://gone:1");
+        assert_eq!(list.tiers()[1], [other, last]);
+
+        let meta = Metainfo::from_bytes(
+            b"d8:announce9:udp://x:113:announce-listll9:udp://a:1ee4:infod6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        )
+        .unwrap();
+        assert_eq!(TrackerList::new(&meta).tiers(), [["udp://a:1"]]);
+    }
+
+    #[test]
+    fn test_failover() {
+        tokio_test::block_on(async {
+            // nothing listens on the first tracker's port.
+            let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let dead_url = format!("http://{}/announce", dead.local_addr().unwrap());
+            drop(dead);
+            let live = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let live_url = format!("http://{}/announce", live.local_addr().unwrap());
+            let server = tokio::spawn(async move {
+                let (mut s, _) = live.accept().await.unwrap();
+                let mut buf = vec![0; 1024];
+                let _ = s.read(&mut buf).await.unwrap();
+                let body = b"d8:intervali900e5:peers0:e";
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                s.write_all(head.as_bytes()).await.unwrap();
+                s.write_all(body).await.unwrap();
+            });
+
+            let mut list = TrackerList {
+                tiers: vec![vec![dead_url, live_url.clone()]],
+            };
+            let req = AnnounceRequest::new([1; 20], [2; 20], 6881);
+            let (resp, i) = announce_urls(&list.tiers()[0], &req).await.unwrap();
+            assert_eq!(resp.interval, Some(900));
+            assert_eq!(i, 1);
+            list.promote_url(0, &live_url);
+            assert_eq!(list.tiers()[0][0], live_url);
+            server.await.unwrap();
+        });
+    }
+}
@@ -1,34 +1,37 @@
 use std::{
+    collections::HashMap,
     fmt::Display,
-    io::Error,
-    net::{SocketAddr, ToSocketAddrs},
-    str::from_utf8,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
 };
 
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use tokio::net::lookup_host;
 
-use crate::bencode::Item;
-
-use self::{http::http_announce, udp::udp_announce};
+use self::{
+    http::{http_announce, http_scrape, TrackerUrl},
+    udp::{udp_announce, udp_scrape},
+};
+pub mod error;
 pub mod http;
+pub mod list;
 pub mod udp;
 
-// computes info_hash from .torrent bytes.
-pub fn get_info_hash(mut bytes: Vec<u8>) -> [u8; 20] {
-    let mut len: usize = 0;
-    for c in bytes.windows(7) {
-        len += 1;
-        if c == b"4:infod" {
-            break;
-        }
-    }
-    bytes.drain(0..len + 5);
-    bytes.pop();
+pub use error::TrackerError;
+pub use list::{announce_urls, TrackerList};
 
+// computes info_hash from the raw bencoded bytes of the info dict.
+pub fn get_info_hash(info: &[u8]) -> [u8; 20] {
     let mut hashser = Sha1::new();
-    hashser.update(&bytes);
+    hashser.update(info);
     hashser.finalize().into()
 }
+
+// BEP 52 v2 info hash, SHA-256 of the same bytes.
+pub fn get_info_hash_v2(info: &[u8]) -> [u8; 32] {
+    Sha256::digest(info).into()
+}
 pub struct IpPort {
     pub ip: u32,
     pub port: u16,
@@ -38,7 +41,7 @@ impl IpPort {
     // takes in byte string of ip:port pairs and parses them
     fn from_bytes(bytes: &[u8]) -> Vec<Self> {
         let mut peers: Vec<IpPort> = vec![];
-        if bytes.len() % 6 != 0 {
+        if !bytes.len().is_multiple_of(6) {
             return peers;
         }
         for chunk in bytes.chunks(6) {
@@ -54,107 +57,301 @@ impl IpPort {
 
         peers
     }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(IpAddr::from(Ipv4Addr::from(self.ip)), self.port)
+    }
+}
+
+// announce events, numbered as in the udp protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
+}
+
+impl Event {
+    // the http `event` value, regular announces leave it out.
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self {
+            Event::None => None,
+            Event::Completed => Some("completed"),
+            Event::Started => Some("started"),
+            Event::Stopped => Some("stopped"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Event,
+    pub numwant: u32,
+    // lets the tracker know us across ip changes.
+    pub key: Option<u32>,
+    // echoed back once a tracker hands us one, opaque bytes.
+    pub tracker_id: Option<Vec<u8>>,
+}
+
+impl AnnounceRequest {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20], port: u16) -> Self {
+        Self {
+            info_hash,
+            peer_id,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: Event::None,
+            numwant: 200,
+            key: None,
+            tracker_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnnounceResponse {
+    // seconds until the next regular announce.
+    pub interval: Option<u32>,
+    // seconds we must wait before announcing again at all.
+    pub min_interval: Option<u32>,
+    pub tracker_id: Option<Vec<u8>>,
+    // seeders
+    pub complete: Option<u32>,
+    // leechers
+    pub incomplete: Option<u32>,
+    pub warning: Option<String>,
+    pub peers: Vec<SocketAddr>,
+}
+
+// swarm health of one torrent, as a scrape reports it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    // how many times the torrent was downloaded in full.
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+// used when the tracker gives no interval.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// intervals shorter than this are raised to it.
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+// retries start at RETRY_BASE and double on every failure, up to RETRY_MAX.
+const RETRY_BASE: Duration = Duration::from_secs(15);
+const RETRY_MAX: Duration = Duration::from_secs(30 * 60);
+
+// when to announce to a tracker next, per info hash.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    next: Instant,
+    failures: u32,
+    pub tracker_id: Option<Vec<u8>>,
+    // whether the tracker has heard our started event.
+    started: bool,
+    // the download finished and the tracker hasn't heard yet.
+    completed: bool,
+}
+
+impl Schedule {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            next: now,
+            failures: 0,
+            tracker_id: None,
+            started: false,
+            completed: false,
+        }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next
+    }
+
+    // started until a tracker hears it, then completed once it's pending.
+    pub fn event(&self) -> Event {
+        match (self.started, self.completed) {
+            (false, _) => Event::Started,
+            (true, true) => Event::Completed,
+            (true, false) => Event::None,
+        }
+    }
+
+    // only trackers that heard started get told we stopped.
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    // the download finished, announces it right away.
+    pub fn complete(&mut self, now: Instant) {
+        self.completed = true;
+        self.next = now;
+    }
+
+    pub fn success(&mut self, resp: &AnnounceResponse, now: Instant) {
+        let secs = |s: Option<u32>| s.map(|s| Duration::from_secs(s as u64));
+        let interval = secs(resp.interval)
+            .unwrap_or(DEFAULT_INTERVAL)
+            .max(secs(resp.min_interval).unwrap_or(Duration::ZERO))
+            .max(MIN_INTERVAL);
+        self.next = now + interval;
+        self.failures = 0;
+        // a pending completed only went out if started already had.
+        if self.started {
+            self.completed = false;
+        }
+        self.started = true;
+        if resp.tracker_id.is_some() {
+            self.tracker_id = resp.tracker_id.clone();
+        }
+    }
+
+    // backs off exponentially, returns how long until the retry.
+    pub fn failure(&mut self, now: Instant) -> Duration {
+        let delay = RETRY_BASE
+            .saturating_mul(1 << self.failures.min(16))
+            .min(RETRY_MAX);
+        self.failures += 1;
+        self.next = now + delay;
+        delay
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum Addr {
     Udp(SocketAddr),
-    Http(SocketAddr),
+    Http(SocketAddr, TrackerUrl),
 }
 
 impl Display for Addr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match *self {
+        match self {
             Addr::Udp(s) => write!(f, "Udp: {}", s),
-            Addr::Http(s) => write!(f, "Http: {}", s),
+            Addr::Http(s, url) => write!(f, "Http: {} {}{}", s, url.host_header(), url.path),
         }
     }
 }
 
-pub fn make_addr(announce: &Item) -> Result<Addr, String> {
-    let mut url = announce.get_string();
-    // get url URI i.e udp://
-    let mut count = 0;
-    let mut len = 0;
-    for c in &url {
-        if count == 2 {
-            break;
-        }
-        if *c == b'/' {
-            count += 1
-        }
-        len += 1;
-    }
-    let mut addr;
-    // handle each URI
-    let udp = url[0] == b'u';
-    match &url[0..len] {
-        b"http://" => url.drain(0.."http://".len()),
-        b"udp://" => url.drain(0.."udp://".len()),
-        b"https://" => return Err("HTTPS/TLS not supported".to_string()),
-        _ => return Err(format!("unknown URI: {}", from_utf8(&url).unwrap())),
+// async, a slow dns server mustn't stall a runtime worker.
+async fn resolve(host: &str, port: u16) -> Result<SocketAddr, String> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match lookup_host((host, port)).await {
+        Ok(mut addrs) => addrs.next().ok_or_else(|| "no addr resolved".to_string()),
+        Err(e) => Err(format!("can't resolve {}: {}", host, e)),
+    }
+}
+
+pub async fn make_addr(announce: &str) -> Result<Addr, String> {
+    let (scheme, rest) = match announce.split_once("://") {
+        Some(s) => s,
+        None => return Err(format!("unknown URI: {}", announce)),
     };
-    // remove any /announce
-    match url.iter().find(|i| **i == b'/') {
-        None => {}
-        Some(_) => loop {
-            url.pop();
-            if *url.last().unwrap() == b'/' {
-                url.pop();
-                break;
-            }
-        },
-    }
-
-    // add port number if none, default 80.
-    addr = from_utf8(&url).unwrap().to_string();
-    match url.last().unwrap() {
-        b'0'..=b'9' => {}
-        _ => addr.push_str(":80"),
-    }
-
-    // resole socket addr
-    match addr.to_socket_addrs().unwrap().next() {
-        Some(s) => {
-            if udp {
-                Ok(Addr::Udp(s))
-            } else {
-                Ok(Addr::Http(s))
-            }
+    match scheme {
+        "http" => {
+            let url = TrackerUrl::parse(announce)?;
+            Ok(Addr::Http(resolve(&url.host, url.port).await?, url))
         }
-        None => Err("no addr resolved".to_string()),
-    }
-}
-
-// gets announce url
-// 1. remove URI and /announce.
-// 2. check if udp.
-// 3. get default port if not seted.
-// 4. check if addr was a valid socket address.
-pub fn get_addr(tree: &Vec<Item>) -> Result<Addr, String> {
-    let dict = tree[0].get_dict();
-    match dict.get("announce".as_bytes()) {
-        Some(s) => match make_addr(&s) {
-            Ok(s) => Ok(s),
-            Err(e) => match dict.get("announce-list".as_bytes()) {
-                Some(l) => {
-                    for i in l.get_list() {
-                        if let Ok(s) = make_addr(&i.get_list()[0]) {
-                            return Ok(s);
-                        }
-                    }
-                    Err(e)
-                }
-                None => Err(e),
-            },
-        },
-        None => Err("no announce url found".to_string()),
-    }
-}
-
-pub async fn announce(addr: Addr, info_hash: [u8; 20], port: u16) -> Result<Vec<IpPort>, Error> {
+        // udp trackers only need host and port, default 80.
+        "udp" => {
+            let authority = rest.split(['/', '?']).next().unwrap_or_default();
+            let (host, port) = match authority.rsplit_once(':') {
+                Some((h, p)) if !p.contains(']') => (
+                    h,
+                    p.parse()
+                        .map_err(|_| format!("invalid port in url: {}", announce))?,
+                ),
+                _ => (authority, 80),
+            };
+            Ok(Addr::Udp(resolve(host, port).await?))
+        }
+        "https" => Err("HTTPS/TLS not supported".to_string()),
+        _ => Err(format!("unknown URI: {}", announce)),
+    }
+}
+
+pub async fn announce(
+    addr: &Addr,
+    req: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
     match addr {
-        Addr::Http(a) => http_announce(a, info_hash, port).await,
-        Addr::Udp(a) => udp_announce(a, info_hash, port).await,
+        Addr::Http(a, url) => http_announce(*a, url, req).await,
+        Addr::Udp(a) => udp_announce(*a, req).await,
+    }
+}
+
+// asks the tracker at addr about info_hashes without announcing.
+pub async fn scrape(
+    addr: &Addr,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    match addr {
+        Addr::Http(a, url) => http_scrape(*a, url, info_hashes).await,
+        Addr::Udp(a) => udp_scrape(*a, info_hashes).await,
+    }
+}
+
+#[cfg(test)]
+mod tracker_test {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let now = Instant::now();
+        let mut s = Schedule::new(now);
+        assert!(s.is_due(now));
+        assert_eq!(s.event(), Event::Started);
+
+        // doubling backoff, capped.
+        assert_eq!(s.failure(now), RETRY_BASE);
+        assert_eq!(s.failure(now), RETRY_BASE * 2);
+        assert!(!s.is_due(now + RETRY_BASE));
+        for _ in 0..20 {
+            s.failure(now);
+        }
+        assert_eq!(s.failure(now), RETRY_MAX);
+        assert_eq!(s.event(), Event::Started);
+
+        let resp = AnnounceResponse {
+            interval: Some(120),
+            min_interval: Some(300),
+            tracker_id: Some(b"abc".to_vec()),
+            ..AnnounceResponse::default()
+        };
+        s.success(&resp, now);
+        assert_eq!(s.event(), Event::None);
+        assert_eq!(s.tracker_id.as_deref(), Some(&b"abc"[..]));
+        // min interval wins over a shorter interval.
+        assert!(!s.is_due(now + Duration::from_secs(299)));
+        assert!(s.is_due(now + Duration::from_secs(300)));
+        // a later response without an id keeps the old one, and failures reset.
+        s.success(&AnnounceResponse::default(), now);
+        assert_eq!(s.tracker_id.as_deref(), Some(&b"abc"[..]));
+        assert!(s.is_due(now + DEFAULT_INTERVAL));
+        assert_eq!(s.failure(now), RETRY_BASE);
+
+        // completed is due at once and sent until a tracker hears it.
+        s.complete(now);
+        assert!(s.is_due(now));
+        assert_eq!(s.event(), Event::Completed);
+        s.failure(now);
+        assert_eq!(s.event(), Event::Completed);
+        s.success(&AnnounceResponse::default(), now);
+        assert_eq!(s.event(), Event::None);
+
+        // finishing before started went out sends both, in order.
+        let mut s = Schedule::new(now);
+        s.complete(now);
+        assert_eq!(s.event(), Event::Started);
+        s.success(&AnnounceResponse::default(), now);
+        assert!(s.is_started());
+        assert_eq!(s.event(), Event::Completed);
     }
 }
@@ -1,16 +1,81 @@
+// BEP 15 udp tracker protocol.
 #![allow(dead_code)]
 
 use serde::{Deserialize, Serialize};
-use std::{io::Error, net::SocketAddr};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
 
-use tokio::net::UdpSocket;
+use tokio::{net::UdpSocket, time};
 
-use super::IpPort;
+use super::{AnnounceRequest, AnnounceResponse, IpPort, ScrapeStats, TrackerError};
 use rand::random;
 
 // literal magic number used for handshake
 const MAGIC: u64 = 0x0417_2710_1980;
 
+const CONNECT: u32 = 0;
+const ANNOUNCE: u32 = 1;
+const SCRAPE: u32 = 2;
+const ERROR: u32 = 3;
+
+// a connection id stays valid for a minute after the tracker hands it out.
+const CONNECTION_TTL: Duration = Duration::from_secs(60);
+const MAX_PACKET: usize = 32767;
+// info hashes per scrape request, BEP 15 caps it at about 74.
+const SCRAPE_BATCH: usize = 74;
+
+// connection ids per tracker, shared by every request to it.
+static CONNECTIONS: LazyLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> =
+    LazyLock::new(Default::default);
+
+// how long to wait for a reply before retransmitting, base·2^n for n up to retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub base: Duration,
+    pub retries: u32,
+}
+
+impl Backoff {
+    // BEP 15, 15·2^n seconds with n up to 8.
+    pub const SPEC: Backoff = Backoff {
+        base: Duration::from_secs(15),
+        retries: 8,
+    };
+
+    fn timeout(&self, n: u32) -> Duration {
+        self.base.saturating_mul(1 << n.min(16))
+    }
+
+    // how long a request takes when every try times out.
+    pub const fn total(&self) -> Duration {
+        let retries = if self.retries < 16 { self.retries } else { 16 };
+        self.base.saturating_mul((2 << retries) - 1)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::SPEC
+    }
+}
+
+fn cached_connection(addr: &SocketAddr, now: Instant) -> Option<u64> {
+    let conns = CONNECTIONS.lock().unwrap();
+    match conns.get(addr) {
+        Some((id, at)) if now.duration_since(*at) < CONNECTION_TTL => Some(*id),
+        _ => None,
+    }
+}
+
+fn forget_connection(addr: &SocketAddr) {
+    CONNECTIONS.lock().unwrap().remove(addr);
+}
+
 // structs to be (de)serialized and sent/received
 #[derive(Debug, Serialize, Deserialize)]
 struct ConnectReq {
@@ -19,19 +84,17 @@ struct ConnectReq {
     transaction_id: u32,
 }
 
+// starts every request after the connect.
 #[derive(Debug, Serialize, Deserialize)]
-struct ConnectResp {
+struct Header {
+    connection_id: u64,
     action: u32,
     transaction_id: u32,
-    connection_id: u64,
 }
 
-// https://wiki.theory.org/index.php/BitTorrentSpecification#Tracker_HTTP_wire_protocol
+// the rest of an announce request, after the header.
 #[derive(Debug, Serialize, Deserialize)]
 struct AnnounceReq {
-    connection_id: u64,
-    action: u32,
-    transaction_id: u32,
     info_hash: [u8; 20],
     peer_id: [u8; 20],
     downloaded: u64,
@@ -44,61 +107,393 @@ struct AnnounceReq {
     port: u16,
 }
 
+fn be_u32(bytes: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap())
+}
+
+pub struct UdpTracker {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    backoff: Backoff,
+}
+
+impl UdpTracker {
+    // the socket is connected, so datagrams from anyone but the tracker never arrive.
+    pub async fn bind(addr: SocketAddr, backoff: Backoff) -> io::Result<Self> {
+        let local = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+        Ok(Self {
+            socket,
+            addr,
+            backoff,
+        })
+    }
+
+    // the reply to transaction_id, None if it didn't come within wait.
+    // replies to other transactions are dropped.
+    async fn recv_reply(
+        &self,
+        action: u32,
+        transaction_id: u32,
+        wait: Duration,
+    ) -> Result<Option<Vec<u8>>, TrackerError> {
+        let deadline = Instant::now() + wait;
+        let mut buf = vec![0; MAX_PACKET];
+        loop {
+            let n = match time::timeout_at(deadline.into(), self.socket.recv(&mut buf)).await {
+                Ok(n) => n?,
+                Err(_) => return Ok(None),
+            };
+            if n < 8 || be_u32(&buf, 4) != transaction_id {
+                continue;
+            }
+            return match be_u32(&buf, 0) {
+                a if a == action => Ok(Some(buf[..n].to_vec())),
+                ERROR => {
+                    forget_connection(&self.addr);
+                    Err(TrackerError::Failure(
+                        String::from_utf8_lossy(&buf[8..n]).into_owned(),
+                    ))
+                }
+                a => Err(TrackerError::Decode(format!(
+                    "expected action {}, got {}",
+                    action, a
+                ))),
+            };
+        }
+    }
+
+    // counts a timed out try, failing once every retry is used up.
+    fn retry(&self, n: &mut u32) -> Result<(), TrackerError> {
+        if *n >= self.backoff.retries {
+            return Err(TrackerError::Timeout);
+        }
+        *n += 1;
+        Ok(())
+    }
+
+    // a cached connection id if it's fresh, else a new one from the tracker.
+    async fn connect(&self, n: &mut u32) -> Result<u64, TrackerError> {
+        if let Some(id) = cached_connection(&self.addr, Instant::now()) {
+            return Ok(id);
+        }
+        loop {
+            let transaction_id = random::<u32>();
+            let req = ConnectReq {
+                protocol_id: u64::to_be(MAGIC),
+                action: u32::to_be(CONNECT),
+                transaction_id: u32::to_be(transaction_id),
+            };
+            self.socket.send(&bincode::serialize(&req).unwrap()).await?;
+            let wait = self.backoff.timeout(*n);
+            if let Some(resp) = self.recv_reply(CONNECT, transaction_id, wait).await? {
+                if resp.len() < 16 {
+                    return Err(TrackerError::Decode("short connect response".to_string()));
+                }
+                let id = u64::from_be_bytes(resp[8..16].try_into().unwrap());
+                CONNECTIONS
+                    .lock()
+                    .unwrap()
+                    .insert(self.addr, (id, Instant::now()));
+                return Ok(id);
+            }
+            self.retry(n)?;
+        }
+    }
+
+    // sends body after a header for action, retransmitting until a reply comes.
+    // the connection id is renewed if it expires between tries.
+    async fn request(&self, action: u32, body: &[u8]) -> Result<Vec<u8>, TrackerError> {
+        let mut n = 0;
+        loop {
+            let connection_id = self.connect(&mut n).await?;
+            let transaction_id = random::<u32>();
+            let header = Header {
+                connection_id: u64::to_be(connection_id),
+                action: u32::to_be(action),
+                transaction_id: u32::to_be(transaction_id),
+            };
+            let mut msg = bincode::serialize(&header).unwrap();
+            msg.extend_from_slice(body);
+            self.socket.send(&msg).await?;
+            let wait = self.backoff.timeout(n);
+            if let Some(resp) = self.recv_reply(action, transaction_id, wait).await? {
+                return Ok(resp);
+            }
+            self.retry(&mut n)?;
+        }
+    }
+
+    pub async fn announce(&self, req: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+        let body = AnnounceReq {
+            info_hash: req.info_hash,
+            peer_id: req.peer_id,
+            downloaded: u64::to_be(req.downloaded),
+            left: u64::to_be(req.left),
+            uploaded: u64::to_be(req.uploaded),
+            event: u32::to_be(req.event as u32),
+            ip_address: 0,
+            key: u32::to_be(req.key.unwrap_or(0)),
+            num_want: u32::to_be(req.numwant),
+            port: u16::to_be(req.port),
+        };
+        let resp = self
+            .request(ANNOUNCE, &bincode::serialize(&body).unwrap())
+            .await?;
+        if resp.len() < 20 {
+            return Err(TrackerError::Decode("short announce response".to_string()));
+        }
+        // action, transaction id, interval, leechers, seeders, then peers.
+        // trackers answer over ipv6 with 18 byte peers.
+        let peers = match self.addr {
+            SocketAddr::V4(_) => IpPort::from_bytes(&resp[20..])
+                .iter()
+                .map(IpPort::addr)
+                .collect(),
+            SocketAddr::V6(_) => resp[20..]
+                .chunks_exact(18)
+                .map(|c| {
+                    let ip = <[u8; 16]>::try_from(&c[..16]).unwrap();
+                    SocketAddr::from((ip, u16::from_be_bytes([c[16], c[17]])))
+                })
+                .collect(),
+        };
+        Ok(AnnounceResponse {
+            interval: Some(be_u32(&resp, 8)),
+            incomplete: Some(be_u32(&resp, 12)),
+            complete: Some(be_u32(&resp, 16)),
+            peers,
+            ..AnnounceResponse::default()
+        })
+    }
+
+    // seeders, completed and leechers for each of info_hashes, in order.
+    pub async fn scrape(&self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let resp = self.request(SCRAPE, &info_hashes.concat()).await?;
+        if resp.len() < 8 + 12 * info_hashes.len() {
+            return Err(TrackerError::Decode("short scrape response".to_string()));
+        }
+        Ok(resp[8..]
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|c| ScrapeStats {
+                seeders: be_u32(c, 0),
+                completed: be_u32(c, 4),
+                leechers: be_u32(c, 8),
+            })
+            .collect())
+    }
+}
+
 pub async fn udp_announce(
     addr: SocketAddr,
-    info_hash: [u8; 20],
-    port: u16,
-) -> Result<Vec<IpPort>, Error> {
-    // set up udp socket
-    let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
-
-    // init structs and serialize
-    let conreq = ConnectReq {
-        protocol_id: u64::to_be(MAGIC),
-        action: 0,
-        transaction_id: random::<u32>(),
-    };
-    let mut conresp = ConnectResp {
-        action: 0,
-        transaction_id: 0,
-        connection_id: 0,
-    };
-    let mut serreq = bincode::serialize(&conreq).unwrap();
-    let mut serresp = bincode::serialize(&conresp).unwrap();
-
-    // send connection request and get response
-    socket.send_to(&serreq, addr).await?;
-    socket.recv_from(&mut serresp).await?;
-
-    // deseriallize struct and check tx id
-    conresp = bincode::deserialize(&serresp).unwrap();
-
-    // init structs and serialize
-    let announce_req = AnnounceReq {
-        connection_id: conresp.connection_id,
-        action: u32::to_be(1),
-        transaction_id: random::<u32>(),
-        info_hash,
-        peer_id: [1; 20],
-        downloaded: 0,
-        left: 0,
-        uploaded: 0,
-        event: 0,
-        ip_address: 0,
-        key: 0,
-        num_want: u32::to_be(200),
-        port: u16::to_be(port),
+    req: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
+    UdpTracker::bind(addr, Backoff::SPEC)
+        .await?
+        .announce(req)
+        .await
+}
+
+// scrapes in batches, all over the same connection id.
+pub async fn udp_scrape(
+    addr: SocketAddr,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    let tracker = UdpTracker::bind(addr, Backoff::SPEC).await?;
+    let mut stats = HashMap::new();
+    for batch in info_hashes.chunks(SCRAPE_BATCH) {
+        let resp = tracker.scrape(batch).await?;
+        stats.extend(batch.iter().copied().zip(resp));
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod udp_test {
+    use super::*;
+    use crate::tracker::Event;
+
+    const FAST: Backoff = Backoff {
+        base: Duration::from_millis(50),
+        retries: 3,
     };
-    serreq = bincode::serialize(&announce_req).unwrap();
-    let mut resp_buf = vec![0_u8; 32767];
 
-    // send announce request and get response
-    socket.send_to(&serreq, addr).await?;
-    let bytes = socket.recv_from(&mut resp_buf).await?.0;
+    // the next datagram to the fake tracker, with its sender.
+    async fn recv(socket: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+        let mut buf = vec![0; MAX_PACKET];
+        let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+        buf.truncate(n);
+        (buf, from)
+    }
+
+    fn reply(action: u32, transaction_id: &[u8], rest: &[u8]) -> Vec<u8> {
+        let mut msg = action.to_be_bytes().to_vec();
+        msg.extend_from_slice(transaction_id);
+        msg.extend_from_slice(rest);
+        msg
+    }
+
+    #[test]
+    fn test_announce() {
+        tokio_test::block_on(async {
+            let fake = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = fake.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                // the first connect goes unanswered and is retransmitted.
+                let (first, _) = recv(&fake).await;
+                let (msg, from) = recv(&fake).await;
+                assert_eq!(&msg[..12], &first[..12]);
+                assert_eq!(msg[..8], MAGIC.to_be_bytes());
+                assert_eq!(be_u32(&msg, 8), CONNECT);
+                let id = 0xdead_beef_u64.to_be_bytes();
+                let resp = reply(CONNECT, &msg[12..16], &id);
+                fake.send_to(&resp, from).await.unwrap();
+
+                for _ in 0..2 {
+                    let (msg, from) = recv(&fake).await;
+                    assert_eq!(msg.len(), 98);
+                    assert_eq!(msg[..8], id);
+                    assert_eq!(be_u32(&msg, 8), ANNOUNCE);
+                    assert_eq!(&msg[16..36], &[1; 20]);
+                    assert_eq!(&msg[36..56], &[2; 20]);
+                    assert_eq!(msg[56..64], 100_u64.to_be_bytes());
+                    assert_eq!(msg[64..72], 50_u64.to_be_bytes());
+                    assert_eq!(msg[72..80], 25_u64.to_be_bytes());
+                    assert_eq!(be_u32(&msg, 80), Event::Started as u32);
+                    assert_eq!(msg[96..98], 6881_u16.to_be_bytes());
+                    // a stray reply to another transaction is ignored.
+                    fake.send_to(&reply(ANNOUNCE, &[0; 4], &[0; 12]), from)
+                        .await
+                        .unwrap();
+                    let mut rest = [900_u32, 3, 7].map(u32::to_be_bytes).concat();
+                    rest.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+                    let resp = reply(ANNOUNCE, &msg[12..16], &rest);
+                    fake.send_to(&resp, from).await.unwrap();
+                }
+            });
+
+            let req = AnnounceRequest {
+                downloaded: 100,
+                left: 50,
+                uploaded: 25,
+                event: Event::Started,
+                ..AnnounceRequest::new([1; 20], [2; 20], 6881)
+            };
+            let tracker = UdpTracker::bind(addr, FAST).await.unwrap();
+            let resp = tracker.announce(&req).await.unwrap();
+            assert_eq!(resp.interval, Some(900));
+            assert_eq!(resp.incomplete, Some(3));
+            assert_eq!(resp.complete, Some(7));
+            assert_eq!(resp.peers, ["127.0.0.1:6881".parse().unwrap()]);
+
+            // the connection id is reused, the fake only answers one connect.
+            let tracker = UdpTracker::bind(addr, FAST).await.unwrap();
+            assert_eq!(tracker.announce(&req).await.unwrap().interval, Some(900));
+            server.await.unwrap();
+
+            let later = Instant::now() + CONNECTION_TTL;
+            assert_eq!(cached_connection(&addr, later), None);
+        });
+    }
+
+    #[test]
+    fn test_scrape() {
+        tokio_test::block_on(async {
+            let fake = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = fake.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let (msg, from) = recv(&fake).await;
+                let resp = reply(CONNECT, &msg[12..16], &9_u64.to_be_bytes());
+                fake.send_to(&resp, from).await.unwrap();
+                // a full batch, then the one hash left over.
+                let mut batches = vec![];
+                for _ in 0..2 {
+                    let (msg, from) = recv(&fake).await;
+                    assert_eq!(be_u32(&msg, 8), SCRAPE);
+                    let hashes: Vec<u8> = msg[16..].chunks(20).map(|h| h[0]).collect();
+                    let rest: Vec<u8> = hashes
+                        .iter()
+                        .flat_map(|h| [*h as u32, 1, 2].map(u32::to_be_bytes).concat())
+                        .collect();
+                    fake.send_to(&reply(SCRAPE, &msg[12..16], &rest), from)
+                        .await
+                        .unwrap();
+                    batches.push(hashes.len());
+                }
+                batches
+            });
+
+            let hashes: Vec<[u8; 20]> = (0..=SCRAPE_BATCH as u8).map(|i| [i; 20]).collect();
+            let stats = udp_scrape(addr, &hashes).await.unwrap();
+            assert_eq!(server.await.unwrap(), [SCRAPE_BATCH, 1]);
+            assert_eq!(stats.len(), hashes.len());
+            let want = ScrapeStats {
+                seeders: 74,
+                completed: 1,
+                leechers: 2,
+            };
+            assert_eq!(stats[&[74; 20]], want);
+        });
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(Backoff::SPEC.timeout(8), Duration::from_secs(15 * 256));
+        assert_eq!(Backoff::SPEC.total(), Duration::from_secs(15 * 511));
+    }
+
+    #[test]
+    fn test_errors() {
+        tokio_test::block_on(async {
+            let fake = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = fake.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let (msg, from) = recv(&fake).await;
+                let resp = reply(CONNECT, &msg[12..16], &7_u64.to_be_bytes());
+                fake.send_to(&resp, from).await.unwrap();
+                let (msg, from) = recv(&fake).await;
+                let resp = reply(ERROR, &msg[12..16], b"go away");
+                fake.send_to(&resp, from).await.unwrap();
+                // the error dropped the connection id, so we connect again.
+                let (msg, from) = recv(&fake).await;
+                assert_eq!(be_u32(&msg, 8), CONNECT);
+                let resp = reply(CONNECT, &msg[12..16], &7_u64.to_be_bytes());
+                fake.send_to(&resp, from).await.unwrap();
+                let (msg, from) = recv(&fake).await;
+                let resp = reply(CONNECT, &msg[12..16], &[0; 16]);
+                fake.send_to(&resp, from).await.unwrap();
+            });
 
-    resp_buf.truncate(bytes);
-    resp_buf.drain(0..20);
+            let req = AnnounceRequest::new([1; 20], [2; 20], 6881);
+            let tracker = UdpTracker::bind(addr, FAST).await.unwrap();
+            match tracker.announce(&req).await {
+                Err(TrackerError::Failure(e)) => assert_eq!(e, "go away"),
+                r => panic!("expected a failure, got {:?}", r),
+            }
+            assert!(matches!(
+                tracker.announce(&req).await,
+                Err(TrackerError::Decode(_))
+            ));
+            server.await.unwrap();
 
-    // deserialize and return peers
-    Ok(IpPort::from_bytes(&resp_buf))
+            // a tracker that never answers times out after every retry.
+            let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let backoff = Backoff {
+                base: Duration::from_millis(10),
+                retries: 1,
+            };
+            let tracker = UdpTracker::bind(silent.local_addr().unwrap(), backoff)
+                .await
+                .unwrap();
+            assert!(matches!(
+                tracker.announce(&req).await,
+                Err(TrackerError::Timeout)
+            ));
+        });
+    }
 }